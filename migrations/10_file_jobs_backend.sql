@@ -0,0 +1 @@
+alter table file_jobs add column if not exists backend TEXT;
@@ -0,0 +1,9 @@
+create table if not exists placeholders
+(
+    file_full_path TEXT PRIMARY KEY,
+    blurhash TEXT NOT NULL,
+    width INT NOT NULL,
+    height INT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    CONSTRAINT fk_file_full_path FOREIGN KEY (file_full_path) REFERENCES files(file_full_path)
+);
@@ -0,0 +1,7 @@
+create table if not exists perceptual_hashes
+(
+    file_full_path TEXT PRIMARY KEY,
+    dhash BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    CONSTRAINT fk_file_full_path FOREIGN KEY (file_full_path) REFERENCES files(file_full_path)
+);
@@ -0,0 +1,3 @@
+alter table file_jobs add column if not exists attempts INT NOT NULL DEFAULT 0;
+alter table file_jobs add column if not exists next_attempt_at TIMESTAMP;
+CREATE INDEX idx_file_jobs_next_attempt_at ON file_jobs (next_attempt_at);
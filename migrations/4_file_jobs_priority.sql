@@ -0,0 +1,3 @@
+alter table file_jobs add column if not exists priority BIGINT NOT NULL DEFAULT 0;
+CREATE INDEX idx_file_jobs_preset_name_priority ON file_jobs (preset_name, priority DESC);
+alter table files add column if not exists captured_at TIMESTAMP;
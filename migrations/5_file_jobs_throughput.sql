@@ -0,0 +1,3 @@
+alter table file_jobs add column if not exists processing_seconds DOUBLE PRECISION;
+alter table file_jobs add column if not exists megapixels DOUBLE PRECISION;
+alter table file_jobs add column if not exists fps INT;
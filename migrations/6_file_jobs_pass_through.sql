@@ -0,0 +1 @@
+alter table file_jobs add column if not exists pass_through BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,22 @@
+create table if not exists live_photos
+(
+    still_full_path TEXT PRIMARY KEY,
+    clip_full_path TEXT NOT NULL UNIQUE,
+    content_identifier TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    CONSTRAINT fk_still_full_path FOREIGN KEY (still_full_path) REFERENCES files(file_full_path),
+    CONSTRAINT fk_clip_full_path FOREIGN KEY (clip_full_path) REFERENCES files(file_full_path)
+);
+
+-- Same stem stills and clips whose content identifiers differ, not read again until one changes.
+create table if not exists rejected_live_photo_candidates
+(
+    still_full_path TEXT NOT NULL,
+    clip_full_path TEXT NOT NULL,
+    still_modified_at TIMESTAMP NOT NULL,
+    clip_modified_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (still_full_path, clip_full_path),
+    CONSTRAINT fk_still_full_path FOREIGN KEY (still_full_path) REFERENCES files(file_full_path),
+    CONSTRAINT fk_clip_full_path FOREIGN KEY (clip_full_path) REFERENCES files(file_full_path)
+);
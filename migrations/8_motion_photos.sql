@@ -0,0 +1,8 @@
+create table if not exists motion_photos
+(
+    photo_full_path TEXT PRIMARY KEY,
+    video_full_path TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL,
+    CONSTRAINT fk_photo_full_path FOREIGN KEY (photo_full_path) REFERENCES files(file_full_path),
+    CONSTRAINT fk_video_full_path FOREIGN KEY (video_full_path) REFERENCES files(file_full_path)
+);
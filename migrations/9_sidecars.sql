@@ -0,0 +1,10 @@
+create table if not exists sidecars
+(
+    sidecar_full_path TEXT NOT NULL,
+    file_full_path TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (sidecar_full_path, file_full_path),
+    CONSTRAINT fk_sidecar_full_path FOREIGN KEY (sidecar_full_path) REFERENCES files(file_full_path),
+    CONSTRAINT fk_file_full_path FOREIGN KEY (file_full_path) REFERENCES files(file_full_path)
+);
+CREATE INDEX idx_sidecars_fk_file_full_path ON sidecars (file_full_path);
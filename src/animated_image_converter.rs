@@ -0,0 +1,160 @@
+use crate::command_runner::{CommandLine, CommandRunner};
+use crate::config::parse_preset_values;
+use crate::converter::{Converter, Throughput};
+use crate::image_converter::resize_size;
+use crate::processor::FileToBeProcessed;
+use crate::sidecar::sidecar_commands;
+use crate::AppContext;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Used for presets missing from the animated image formats setting.
+const DEFAULT_FORMAT: AnimatedImageFormat = AnimatedImageFormat::Webp;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnimatedImageFormat {
+    Webp,
+    Mp4,
+}
+
+impl AnimatedImageFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            AnimatedImageFormat::Webp => "webp",
+            AnimatedImageFormat::Mp4 => "mp4",
+        }
+    }
+}
+
+impl FromStr for AnimatedImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "webp" => Ok(AnimatedImageFormat::Webp),
+            "mp4" => Ok(AnimatedImageFormat::Mp4),
+            _ => Err(format!(
+                "unknown animated image format {s}, expected webp or mp4"
+            )),
+        }
+    }
+}
+
+/// Converts animated GIF, WebP and HEIF sequences to an animated WebP or a silent H.264 MP4,
+/// depending on the preset. Still images, including the ones with several pages, are left to the
+/// image converter.
+pub struct AnimatedImageConverter {
+    formats: HashMap<String, AnimatedImageFormat>,
+}
+
+impl AnimatedImageConverter {
+    pub fn new(ctx: &AppContext) -> AnimatedImageConverter {
+        AnimatedImageConverter {
+            formats: parse_preset_values(
+                "ANIMATED_IMAGE_FORMATS",
+                &ctx.config.animated_image_formats,
+            ),
+        }
+    }
+
+    fn format(&self, preset_name: &str) -> AnimatedImageFormat {
+        self.formats
+            .get(preset_name)
+            .copied()
+            .unwrap_or(DEFAULT_FORMAT)
+    }
+}
+
+impl Converter for AnimatedImageConverter {
+    fn name(&self) -> &'static str {
+        "animated-image"
+    }
+
+    fn handles(&self, file: &FileToBeProcessed) -> bool {
+        file.is_image() && file.exif.is_animated()
+    }
+
+    fn output_extension(&self, file: &FileToBeProcessed) -> String {
+        self.format(file.preset_name).extension().to_owned()
+    }
+
+    /// ffmpeg decodes GIFs and HEIF sequences, ImageMagick handles the rest and also writes MP4s
+    /// through its ffmpeg delegate.
+    fn commands(
+        &self,
+        file: &FileToBeProcessed,
+        output: &Path,
+    ) -> Vec<(&'static str, CommandRunner)> {
+        let runner = |command_line: CommandLine| {
+            CommandRunner::build(file.output_folder, file.shutdown)
+                .with_timeout(file.timeout)
+                .create_folder(file.output_folder_path())
+                .with(command_line)
+                .with_all(sidecar_commands(file, output))
+                .copy_file_modification_date(file.file_full_path(), output)
+        };
+        let encode = match self.format(file.preset_name) {
+            AnimatedImageFormat::Webp => encode_webp(file, output),
+            AnimatedImageFormat::Mp4 => encode_mp4(file, output),
+        };
+        vec![
+            ("ffmpeg", runner(encode)),
+            ("imagemagick", runner(resize_animation(file, output))),
+        ]
+    }
+
+    fn estimate_duration(
+        &self,
+        file: &FileToBeProcessed,
+        throughput: &Throughput,
+    ) -> Option<Duration> {
+        let frames = file.exif.frame_count.unwrap_or(1.0);
+        let seconds = file.exif.megapixels()? * frames / throughput.megapixels_per_second;
+        Duration::try_from_secs_f64(seconds).ok()
+    }
+}
+
+/// Frames are coalesced first, as animations often only store the changes from the previous one.
+fn resize_animation(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    let size = resize_size(file.preset_name);
+    CommandLine::new("convert")
+        .arg(file.file_full_path())
+        .args(["-coalesce", "-resize", &format!("{size}x{size}^")])
+        .args(["-loop", "0"])
+        .arg(output)
+}
+
+/// Loops forever like the GIFs it usually replaces, keeping their transparency.
+fn encode_webp(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    let size = resize_size(file.preset_name);
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y"])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-map", "0:v:0", "-an"])
+        .arg("-vf")
+        .arg(format!(
+            "scale={size}:{size}:force_original_aspect_ratio=increase"
+        ))
+        .args(["-c:v", "libwebp_anim", "-loop", "0"])
+        .arg(output)
+}
+
+/// H.264 needs even dimensions, and a pixel format browsers play.
+fn encode_mp4(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    let size = resize_size(file.preset_name);
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y"])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-map", "0:v:0", "-an"])
+        .arg("-vf")
+        .arg(format!(
+            "scale={size}:{size}:force_original_aspect_ratio=increase,scale=trunc(iw/2)*2:trunc(ih/2)*2"
+        ))
+        .args(["-c:v", "libx264", "-pix_fmt", "yuv420p"])
+        .args(["-movflags", "+faststart"])
+        .arg(output)
+}
@@ -0,0 +1,237 @@
+use crate::db;
+use crate::db::{FilePlaceholder, LivePhoto, MotionPhoto, Sidecar, SimilarFile};
+use crate::errors::FixMyLibErrors;
+use crate::processor::get_preset_names;
+use crate::requeue::{file_job_status, requeue_file_jobs, RequeueArgs};
+use crate::AppContext;
+use axum::extract::{Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+
+pub async fn run(ctx: &AppContext) -> anyhow::Result<()> {
+    let api = Router::new()
+        .route("/api/file-jobs/requeue", post(requeue))
+        .route("/api/live-photos", get(live_photos))
+        .route("/api/motion-photos", get(motion_photos))
+        .route("/api/sidecars", get(sidecars))
+        .route("/api/placeholders", get(placeholders))
+        .route("/api/similar-files", get(similar_files))
+        .route_layer(middleware::from_fn_with_state(ctx.clone(), require_token));
+    let mut app = Router::new().route("/metrics", get(metrics));
+    if api_token(ctx).is_some() || ctx.config.api_address.ip().is_loopback() {
+        app = app.merge(api);
+    } else {
+        warn!(
+            "Not serving the /api routes on {} without API_TOKEN.",
+            ctx.config.api_address
+        );
+    }
+    let app = app.with_state(ctx.clone());
+
+    info!("Serving API on {}", ctx.config.api_address);
+    axum::Server::bind(&ctx.config.api_address)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// The token the `/api` routes require, `API_TOKEN` being set but left empty in the image.
+fn api_token(ctx: &AppContext) -> Option<&str> {
+    ctx.config
+        .api_token
+        .as_deref()
+        .filter(|token| !token.is_empty())
+}
+
+/// Rejects requests without the configured token, if any.
+async fn require_token<B>(
+    State(ctx): State<AppContext>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(token) = api_token(&ctx) {
+        let authorization = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if authorization != Some(&format!("Bearer {token}")) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[derive(serde::Serialize)]
+struct FileJobResponse {
+    file_full_path: String,
+    preset_name: String,
+    status: &'static str,
+    command_log: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct RequeueResponse {
+    dry_run: bool,
+    file_jobs: Vec<FileJobResponse>,
+}
+
+async fn requeue(
+    State(ctx): State<AppContext>,
+    Json(args): Json<RequeueArgs>,
+) -> Result<Json<RequeueResponse>, ApiError> {
+    let file_jobs = requeue_file_jobs(&ctx, &args).await?;
+    Ok(Json(RequeueResponse {
+        dry_run: args.dry_run,
+        file_jobs: file_jobs
+            .into_iter()
+            .map(|job| FileJobResponse {
+                status: file_job_status(&job),
+                file_full_path: job.file_full_path,
+                preset_name: job.preset_name,
+                command_log: job.command_log,
+            })
+            .collect(),
+    }))
+}
+
+const DEFAULT_PAGE_LIMIT: i64 = 100;
+const MAX_PAGE_LIMIT: i64 = 1000;
+
+/// Rows of a listing to return, `limit` of them from `offset`.
+#[derive(serde::Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: i64,
+    #[serde(default = "default_page_limit")]
+    limit: i64,
+}
+
+fn default_page_limit() -> i64 {
+    DEFAULT_PAGE_LIMIT
+}
+
+impl Page {
+    fn validate(&self) -> Result<(), FixMyLibErrors> {
+        if self.offset < 0 || !(1..=MAX_PAGE_LIMIT).contains(&self.limit) {
+            return Err(FixMyLibErrors::InvalidRequest(format!(
+                "offset must not be negative and limit must be between 1 and {MAX_PAGE_LIMIT}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Stills and clips paired as Live Photos. Their outputs mirror the source paths, the clip being
+/// converted by the live preset only.
+async fn live_photos(
+    State(ctx): State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<LivePhoto>>, ApiError> {
+    page.validate()?;
+    Ok(Json(
+        db::get_live_photos(&ctx.db, page.offset, page.limit).await?,
+    ))
+}
+
+/// Motion photos, with the file their video was extracted to. The video presets convert it next
+/// to the photo outputs, named after the photo with a `.motion.mp4` extension.
+async fn motion_photos(
+    State(ctx): State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<MotionPhoto>>, ApiError> {
+    page.validate()?;
+    Ok(Json(
+        db::get_motion_photos(&ctx.db, page.offset, page.limit).await?,
+    ))
+}
+
+/// Sidecar files, with the media file whose outputs get their metadata.
+async fn sidecars(
+    State(ctx): State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<Sidecar>>, ApiError> {
+    page.validate()?;
+    Ok(Json(
+        db::get_sidecars(&ctx.db, page.offset, page.limit).await?,
+    ))
+}
+
+/// BlurHash placeholders of the files, computed from their thumbnail or the poster frame of its
+/// video, to show while the thumbnail loads.
+async fn placeholders(
+    State(ctx): State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<FilePlaceholder>>, ApiError> {
+    page.validate()?;
+    Ok(Json(
+        db::get_placeholders(&ctx.db, page.offset, page.limit).await?,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct SimilarFilesQuery {
+    file_full_path: String,
+    max_distance: Option<u32>,
+}
+
+/// Near-duplicates of a photo, closest first, by the distance between their perceptual hashes.
+async fn similar_files(
+    State(ctx): State<AppContext>,
+    Query(query): Query<SimilarFilesQuery>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<SimilarFile>>, ApiError> {
+    page.validate()?;
+    let max_distance = query
+        .max_distance
+        .unwrap_or(ctx.config.near_duplicate_max_distance);
+    Ok(Json(
+        db::get_similar_files(
+            &ctx.db,
+            &query.file_full_path,
+            max_distance.into(),
+            page.offset,
+            page.limit,
+        )
+        .await?,
+    ))
+}
+
+/// Prometheus metrics. The queue depth is read from the database on each scrape, the rest is
+/// recorded by the scanner and the processor as they run.
+async fn metrics(State(ctx): State<AppContext>) -> Result<impl IntoResponse, ApiError> {
+    for preset_name in get_preset_names(&ctx) {
+        ctx.metrics.set_queue_depth(&preset_name, 0);
+    }
+    for (preset_name, count) in db::count_unfinished_file_jobs(&ctx.db).await? {
+        ctx.metrics.set_queue_depth(&preset_name, count);
+    }
+    Ok((
+        [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        ctx.metrics.encode()?,
+    ))
+}
+
+pub struct ApiError(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        ApiError(e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0.downcast_ref::<FixMyLibErrors>() {
+            Some(FixMyLibErrors::InvalidRequest(_)) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status == StatusCode::INTERNAL_SERVER_ERROR {
+            error!("API request failed: {:?}", self.0);
+        }
+        (status, self.0.to_string()).into_response()
+    }
+}
@@ -0,0 +1,117 @@
+use crate::command_runner::{CommandLine, CommandRunner};
+use crate::config::parse_preset_values;
+use crate::converter::{Converter, Throughput};
+use crate::processor::FileToBeProcessed;
+use crate::AppContext;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Used for presets missing from the audio bitrates setting.
+const DEFAULT_BITRATE_KBPS: u32 = 128;
+/// Typical encoding speed, in seconds of audio per second.
+const ENCODING_SPEED: f64 = 50.0;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+    Mp3,
+}
+
+impl AudioCodec {
+    fn extension(&self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "m4a",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Mp3 => "mp3",
+        }
+    }
+
+    fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "libopus",
+            AudioCodec::Mp3 => "libmp3lame",
+        }
+    }
+}
+
+pub struct AudioConverter {
+    codec: AudioCodec,
+    bitrates_kbps: HashMap<String, u32>,
+}
+
+impl AudioConverter {
+    pub fn new(ctx: &AppContext) -> AudioConverter {
+        AudioConverter {
+            codec: ctx.config.audio_codec,
+            bitrates_kbps: parse_preset_values("AUDIO_BITRATES", &ctx.config.audio_bitrates),
+        }
+    }
+
+    fn bitrate_kbps(&self, preset_name: &str) -> u32 {
+        self.bitrates_kbps
+            .get(preset_name)
+            .copied()
+            .unwrap_or(DEFAULT_BITRATE_KBPS)
+    }
+
+    fn encode_audio(&self, file: &FileToBeProcessed, output: &Path) -> CommandLine {
+        let command = CommandLine::new("ffmpeg")
+            .args(["-nostdin", "-y"])
+            .arg("-i")
+            .arg(file.file_full_path())
+            .args(["-map", "0:a:0", "-map_metadata", "0"])
+            .args(["-c:a", self.codec.encoder()])
+            .args(["-b:a", &format!("{}k", self.bitrate_kbps(file.preset_name))]);
+        // The mp4 muxer writes the usual tags as iTunes atoms by default, which players read.
+        let command = match self.codec {
+            AudioCodec::Mp3 => command.args(["-id3v2_version", "3"]),
+            AudioCodec::Aac | AudioCodec::Opus => command,
+        };
+        command.arg(output)
+    }
+}
+
+impl Converter for AudioConverter {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn handles(&self, file: &FileToBeProcessed) -> bool {
+        file.is_audio()
+    }
+
+    fn output_extension(&self, _file: &FileToBeProcessed) -> String {
+        self.codec.extension().to_owned()
+    }
+
+    fn commands(
+        &self,
+        file: &FileToBeProcessed,
+        output: &Path,
+    ) -> Vec<(&'static str, CommandRunner)> {
+        vec![(
+            self.codec.encoder(),
+            CommandRunner::build(file.output_folder, file.shutdown)
+                .with_timeout(file.timeout)
+                .create_folder(file.output_folder_path())
+                .with(self.encode_audio(file, output))
+                .copy_file_modification_date(file.file_full_path(), output),
+        )]
+    }
+
+    fn estimate_output_size(&self, file: &FileToBeProcessed) -> Option<u64> {
+        let bytes_per_second = self.bitrate_kbps(file.preset_name) as f64 * 1000.0 / 8.0;
+        Some((file.exif.duration? * bytes_per_second) as u64)
+    }
+
+    fn estimate_duration(
+        &self,
+        file: &FileToBeProcessed,
+        _throughput: &Throughput,
+    ) -> Option<Duration> {
+        Duration::try_from_secs_f64(file.exif.duration? / ENCODING_SPEED).ok()
+    }
+}
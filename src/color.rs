@@ -0,0 +1,186 @@
+use lcms2::{
+    CIExyY, CIExyYTRIPLE, ColorSpaceSignature, Intent, Locale, PixelFormat, Profile, Tag,
+    TagSignature, ToneCurve, Transform, MLU,
+};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Used for presets missing from the image color spaces setting.
+pub const DEFAULT_COLOR_SPACE: ColorSpace = ColorSpace::Srgb;
+
+/// Color space of the image outputs, whose ICC profile is embedded in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(ColorSpace::Srgb),
+            "p3" | "display-p3" => Ok(ColorSpace::DisplayP3),
+            _ => Err(format!("unknown color space {s}, expected srgb or p3")),
+        }
+    }
+}
+
+impl Display for ColorSpace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::DisplayP3 => "p3",
+        })
+    }
+}
+
+impl ColorSpace {
+    fn name(&self) -> &'static str {
+        match self {
+            ColorSpace::Srgb => "sRGB",
+            ColorSpace::DisplayP3 => "Display P3",
+        }
+    }
+
+    /// Primaries of the color space, for ffmpeg's zscale filter.
+    pub fn zscale_primaries(&self) -> &'static str {
+        match self {
+            ColorSpace::Srgb => "bt709",
+            ColorSpace::DisplayP3 => "smpte432",
+        }
+    }
+
+    /// ICC profile of the color space, generated once.
+    pub fn icc_profile(&self) -> &'static [u8] {
+        static SRGB: OnceLock<Vec<u8>> = OnceLock::new();
+        static DISPLAY_P3: OnceLock<Vec<u8>> = OnceLock::new();
+        let profile = match self {
+            ColorSpace::Srgb => &SRGB,
+            ColorSpace::DisplayP3 => &DISPLAY_P3,
+        };
+        profile.get_or_init(|| {
+            self.profile()
+                .and_then(|profile| profile.icc())
+                .unwrap_or_else(|e| panic!("Failure generating the {} profile: {e}", self.name()))
+        })
+    }
+
+    /// ICC profile of the color space written to a file, for the external tools. A file left by
+    /// another run or another user is replaced unless it holds the same profile, and the profile
+    /// is written to a new file renamed over it, so tools never read a partial profile.
+    pub fn icc_profile_path(&self) -> io::Result<PathBuf> {
+        let name = format!("fixmylib-{}.icc", self.name().replace(' ', "-"));
+        let path = std::env::temp_dir().join(&name);
+        if std::fs::read(&path).is_ok_and(|profile| profile == self.icc_profile()) {
+            return Ok(path);
+        }
+        let temporary_path =
+            std::env::temp_dir().join(format!(".{name}.{}.tmp", std::process::id()));
+        // Left behind by a crashed run of a process with the same id.
+        let _ = std::fs::remove_file(&temporary_path);
+        let written = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temporary_path)
+            .and_then(|mut file| file.write_all(self.icc_profile()))
+            .and_then(|_| std::fs::rename(&temporary_path, &path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temporary_path);
+        }
+        written.map(|_| path)
+    }
+
+    fn profile(&self) -> lcms2::LCMSResult<Profile> {
+        match self {
+            ColorSpace::Srgb => Ok(Profile::new_srgb()),
+            // Display P3 has the primaries of DCI-P3 with the D65 white point and sRGB curve.
+            ColorSpace::DisplayP3 => {
+                let white_point = CIExyY {
+                    x: 0.3127,
+                    y: 0.3290,
+                    Y: 1.0,
+                };
+                let primaries = CIExyYTRIPLE {
+                    Red: CIExyY {
+                        x: 0.680,
+                        y: 0.320,
+                        Y: 1.0,
+                    },
+                    Green: CIExyY {
+                        x: 0.265,
+                        y: 0.690,
+                        Y: 1.0,
+                    },
+                    Blue: CIExyY {
+                        x: 0.150,
+                        y: 0.060,
+                        Y: 1.0,
+                    },
+                };
+                let curve = ToneCurve::new_parametric(
+                    4,
+                    &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045],
+                )?;
+                let mut profile =
+                    Profile::new_rgb(&white_point, &primaries, &[&curve, &curve, &curve])?;
+                let mut description = MLU::new(1);
+                description.set_text(self.name(), Locale::none());
+                profile.write_tag(TagSignature::ProfileDescriptionTag, Tag::MLU(&description));
+                Ok(profile)
+            }
+        }
+    }
+}
+
+/// Converts 8-bit RGB pixels from the embedded profile of the source, sRGB when there is none, to
+/// the color space.
+pub fn convert_pixels(
+    pixels: &mut [u8],
+    source_profile: Option<&[u8]>,
+    color_space: ColorSpace,
+) -> io::Result<()> {
+    let invalid_data = |e: lcms2::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+    // The decoder already converted gray and CMYK images to RGB, so their profile is ignored.
+    let source = source_profile
+        .map(Profile::new_icc)
+        .transpose()
+        .map_err(invalid_data)?
+        .filter(|profile| profile.color_space() == ColorSpaceSignature::RgbData);
+    let source = match source {
+        Some(source) => source,
+        None if color_space == ColorSpace::Srgb => return Ok(()),
+        None => Profile::new_srgb(),
+    };
+    let target = Profile::new_icc(color_space.icc_profile()).map_err(invalid_data)?;
+    let transform = Transform::new(
+        &source,
+        PixelFormat::RGB_8,
+        &target,
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )
+    .map_err(invalid_data)?;
+    transform.transform_in_place(pixels);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_a_profile_file_holding_another_profile() {
+        let path = ColorSpace::DisplayP3.icc_profile_path().unwrap();
+        std::fs::write(&path, ColorSpace::Srgb.icc_profile()).unwrap();
+        assert_eq!(ColorSpace::DisplayP3.icc_profile_path().unwrap(), path);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            ColorSpace::DisplayP3.icc_profile()
+        );
+    }
+}
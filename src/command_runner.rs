@@ -0,0 +1,622 @@
+use crate::file_copy::{link_or_copy, CopyMethod};
+use crate::motion_photo::extract_motion_photo_video;
+use crate::native_image::{encode_natively, resize_natively, ImageOutput};
+use crate::processor::{remove_temporary_output, ProcessingResult};
+use crate::raw_preview::{keep_largest_preview, preview_path, PREVIEW_TAGS};
+use crate::shutdown::Shutdown;
+use filetime::FileTime;
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use subprocess::{ExitStatus, Popen, PopenConfig, Redirection};
+
+/// A program and its arguments, executed without a shell so paths never need escaping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandLine {
+    program: String,
+    args: Vec<String>,
+    stdout: Option<PathBuf>,
+}
+
+impl CommandLine {
+    pub fn new(program: impl Into<String>) -> CommandLine {
+        CommandLine {
+            program: program.into(),
+            args: vec![],
+            stdout: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<Path>) -> CommandLine {
+        self.args.push(arg.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> CommandLine
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<Path>,
+    {
+        for arg in args {
+            self = self.arg(arg);
+        }
+        self
+    }
+
+    /// Writes the standard output of the program to a file instead of the command log.
+    pub fn stdout_to(mut self, path: impl Into<PathBuf>) -> CommandLine {
+        self.stdout = Some(path.into());
+        self
+    }
+
+    fn argv(&self) -> Vec<&str> {
+        let mut argv = vec![self.program.as_str()];
+        argv.extend(self.args.iter().map(String::as_str));
+        argv
+    }
+}
+
+impl Display for CommandLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let quoted: Vec<String> = self.argv().into_iter().map(shell_quote).collect();
+        write!(f, "{}", quoted.join(" "))?;
+        if let Some(stdout) = &self.stdout {
+            write!(f, " > {}", shell_quote(&stdout.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes an argument so the displayed command can be pasted into a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r#"'\''"#))
+    }
+}
+
+enum Step {
+    CreateFolder(PathBuf),
+    Run(CommandLine),
+    CopyFileModificationDate { from: PathBuf, to: PathBuf },
+    LinkOrCopy { from: PathBuf, to: PathBuf },
+    ExtractEmbeddedPreview {
+        from: PathBuf,
+        to: PathBuf,
+        min_size: u32,
+    },
+    ExtractMotionPhotoVideo {
+        from: PathBuf,
+        to: PathBuf,
+        offset: Option<u64>,
+    },
+    ResizeNatively {
+        from: PathBuf,
+        to: PathBuf,
+        orientation: u8,
+        output: ImageOutput,
+    },
+    EncodeNatively {
+        path: PathBuf,
+        output: ImageOutput,
+    },
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::CreateFolder(path) => {
+                write!(f, "mkdir -p {}", shell_quote(&path.to_string_lossy()))
+            }
+            Step::Run(command_line) => write!(f, "{command_line}"),
+            Step::CopyFileModificationDate { from, to } => write!(
+                f,
+                "touch -r {} {}",
+                shell_quote(&from.to_string_lossy()),
+                shell_quote(&to.to_string_lossy())
+            ),
+            Step::LinkOrCopy { from, to } => write!(
+                f,
+                "link-or-copy {} {}",
+                shell_quote(&from.to_string_lossy()),
+                shell_quote(&to.to_string_lossy())
+            ),
+            Step::ExtractEmbeddedPreview { from, to, min_size } => {
+                for tag in PREVIEW_TAGS {
+                    writeln!(f, "{}", preview_command_line(from, to, tag))?;
+                }
+                write!(
+                    f,
+                    "keep-largest-preview {} min_size={min_size}",
+                    shell_quote(&to.to_string_lossy())
+                )
+            }
+            Step::ExtractMotionPhotoVideo { from, to, .. } => write!(
+                f,
+                "exiftool -b -MotionPhotoVideo {} > {}",
+                shell_quote(&from.to_string_lossy()),
+                shell_quote(&to.to_string_lossy())
+            ),
+            Step::ResizeNatively {
+                from,
+                to,
+                orientation,
+                output,
+            } => write!(
+                f,
+                "native-resize {} {} orientation={orientation} {output}",
+                shell_quote(&from.to_string_lossy()),
+                shell_quote(&to.to_string_lossy())
+            ),
+            Step::EncodeNatively { path, output } => write!(
+                f,
+                "native-encode {} {output}",
+                shell_quote(&path.to_string_lossy())
+            ),
+        }
+    }
+}
+
+/// Extracts the preview stored in `tag` of the RAW file `from`, see [`keep_largest_preview`].
+fn preview_command_line(from: &Path, to: &Path, tag: &str) -> CommandLine {
+    CommandLine::new("exiftool")
+        .arg("-b")
+        .arg(format!("-{tag}"))
+        .arg(from)
+        .stdout_to(preview_path(to, tag))
+}
+
+/// Shell command doing what [`link_or_copy`] did with `method`.
+fn copy_command(method: CopyMethod, from: &Path, to: &Path) -> String {
+    let program = match method {
+        CopyMethod::Reflink => "cp --reflink=always",
+        CopyMethod::Hardlink => "ln",
+        CopyMethod::Copy => "cp",
+    };
+    format!(
+        "{program} {} {}",
+        shell_quote(&from.to_string_lossy()),
+        shell_quote(&to.to_string_lossy())
+    )
+}
+
+enum StepError {
+    Failed(String),
+    TimedOut(String),
+    Interrupted(String),
+}
+
+impl StepError {
+    fn after_output(self, output: &str) -> StepError {
+        match self {
+            StepError::Failed(reason) => StepError::Failed(format!("{output}\n{reason}")),
+            StepError::TimedOut(reason) => StepError::TimedOut(format!("{output}\n{reason}")),
+            StepError::Interrupted(reason) => StepError::Interrupted(format!("{output}\n{reason}")),
+        }
+    }
+}
+
+/// Runs a sequence of steps, stopping at the first one that fails. The timeout covers all steps.
+pub struct CommandRunner {
+    cwd: String,
+    steps: Vec<Step>,
+    shutdown: Shutdown,
+    timeout: Option<Duration>,
+    pass_through: bool,
+}
+
+impl CommandRunner {
+    pub fn build(cwd: impl Into<String>, shutdown: &Shutdown) -> CommandRunner {
+        CommandRunner {
+            cwd: cwd.into(),
+            steps: vec![],
+            shutdown: shutdown.clone(),
+            timeout: None,
+            pass_through: false,
+        }
+    }
+
+    pub fn with(mut self, command_line: CommandLine) -> CommandRunner {
+        self.steps.push(Step::Run(command_line));
+        self
+    }
+
+    pub fn with_all(self, command_lines: Vec<CommandLine>) -> CommandRunner {
+        command_lines.into_iter().fold(self, CommandRunner::with)
+    }
+
+    pub fn create_folder(mut self, path: impl Into<PathBuf>) -> CommandRunner {
+        self.steps.push(Step::CreateFolder(path.into()));
+        self
+    }
+
+    pub fn copy_file_modification_date(
+        mut self,
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+    ) -> CommandRunner {
+        self.steps.push(Step::CopyFileModificationDate {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    /// Places the source at the output, linking it when possible, see [`link_or_copy`].
+    pub fn link_or_copy(
+        mut self,
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+    ) -> CommandRunner {
+        self.steps.push(Step::LinkOrCopy {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    /// Writes the largest JPEG preview embedded in a RAW file to the output, failing when its
+    /// smallest side is under `min_size`, see [`keep_largest_preview`].
+    pub fn extract_embedded_preview(
+        mut self,
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+        min_size: u32,
+    ) -> CommandRunner {
+        self.steps.push(Step::ExtractEmbeddedPreview {
+            from: from.into(),
+            to: to.into(),
+            min_size,
+        });
+        self
+    }
+
+    /// Writes the video appended to a motion photo to `to`, see [`extract_motion_photo_video`].
+    pub fn extract_motion_photo_video(
+        mut self,
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+        offset: Option<u64>,
+    ) -> CommandRunner {
+        self.steps.push(Step::ExtractMotionPhotoVideo {
+            from: from.into(),
+            to: to.into(),
+            offset,
+        });
+        self
+    }
+
+    /// Resizes an image in process to the `output` geometry, rotates it according to its EXIF
+    /// `orientation` and writes it as a JPEG, see [`resize_natively`].
+    pub fn resize_natively(
+        mut self,
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+        orientation: u8,
+        output: ImageOutput,
+    ) -> CommandRunner {
+        self.steps.push(Step::ResizeNatively {
+            from: from.into(),
+            to: to.into(),
+            orientation,
+            output,
+        });
+        self
+    }
+
+    /// Crops and encodes in process the image another tool wrote, see [`encode_natively`].
+    pub fn encode_natively(
+        mut self,
+        path: impl Into<PathBuf>,
+        output: ImageOutput,
+    ) -> CommandRunner {
+        self.steps.push(Step::EncodeNatively {
+            path: path.into(),
+            output,
+        });
+        self
+    }
+
+    /// Marks the steps as passing the source through without transcoding it.
+    pub fn pass_through(mut self) -> CommandRunner {
+        self.pass_through = true;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> CommandRunner {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Human readable reproduction of the steps, one shell command per line.
+    pub fn command(&self) -> String {
+        self.steps
+            .iter()
+            .map(|step| step.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    pub fn run(&self) -> ProcessingResult {
+        self.run_since(Instant::now())
+    }
+
+    /// Runs the steps within the timeout counted from `started_at`, which the fallbacks of a
+    /// conversion share with the attempts before them.
+    pub fn run_since(&self, started_at: Instant) -> ProcessingResult {
+        trace!("Will run command: {}", self.command());
+        let result = ProcessingResult::new();
+        if self.shutdown.is_requested() {
+            return result
+                .with_command(self.command())
+                .with_command_log("Not started because fixmylib is shutting down".to_owned())
+                .interrupted();
+        }
+        let deadline = self.timeout.map(|timeout| started_at + timeout);
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return result
+                .with_command(self.command())
+                .with_command_log(format!(
+                    "Not started because the conversion timed out after {} seconds",
+                    started_at.elapsed().as_secs()
+                ))
+                .failed();
+        }
+        let mut command_log = String::new();
+        // The steps as they ran, with the way each pass through was actually done.
+        let mut commands: Vec<String> = self.steps.iter().map(|step| step.to_string()).collect();
+        for (index, step) in self.steps.iter().enumerate() {
+            let step_result = match step {
+                Step::CreateFolder(path) => std::fs::create_dir_all(path)
+                    .map(|_| String::new())
+                    .map_err(|e| {
+                        StepError::Failed(format!("Failure creating {}: {e}", path.display()))
+                    }),
+                Step::Run(command_line) => self.run_command_line(command_line, started_at),
+                Step::CopyFileModificationDate { from, to } => {
+                    copy_file_modification_date(from, to)
+                        .map(|_| String::new())
+                        .map_err(|e| {
+                            StepError::Failed(format!(
+                                "Failure copying modification date from {} to {}: {e}",
+                                from.display(),
+                                to.display()
+                            ))
+                        })
+                }
+                Step::LinkOrCopy { from, to } => {
+                    let copied = link_or_copy(from, to);
+                    // A failure is the one of the copy, the last resort.
+                    let method = copied.as_ref().map_or(CopyMethod::Copy, |method| *method);
+                    commands[index] = copy_command(method, from, to);
+                    copied
+                        .map(|method| format!("Passed {} through by {method}\n", from.display()))
+                        .map_err(|e| {
+                            StepError::Failed(format!(
+                                "Failure copying {} to {}: {e}",
+                                from.display(),
+                                to.display()
+                            ))
+                        })
+                }
+                Step::ExtractEmbeddedPreview { from, to, min_size } => {
+                    self.run_preview_extraction(from, to, *min_size, started_at)
+                }
+                Step::ExtractMotionPhotoVideo { from, to, offset } => {
+                    extract_motion_photo_video(from, to, *offset)
+                        .map(|size| {
+                            format!(
+                                "Extracted the {size} bytes video of the motion photo {} to {}\n",
+                                from.display(),
+                                to.display()
+                            )
+                        })
+                        .map_err(|e| {
+                            StepError::Failed(format!(
+                                "Failure extracting the video of the motion photo {}: {e}",
+                                from.display()
+                            ))
+                        })
+                }
+                Step::ResizeNatively {
+                    from,
+                    to,
+                    orientation,
+                    output,
+                } => resize_natively(from, to, *orientation, output, deadline)
+                    .map(|(width, height)| {
+                        format!("Resized {} to {width}x{height}\n", from.display())
+                    })
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::TimedOut => timed_out(started_at),
+                        _ => StepError::Failed(format!("Failure resizing {}: {e}", from.display())),
+                    }),
+                Step::EncodeNatively { path, output } => encode_natively(path, output, deadline)
+                    .map(|(width, height)| {
+                        format!("Encoded {} at {width}x{height}\n", path.display())
+                    })
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::TimedOut => timed_out(started_at),
+                        _ => StepError::Failed(format!("Failure encoding {}: {e}", path.display())),
+                    }),
+            };
+            // Steps run in process cannot be killed, so the timeout is checked once they return.
+            let step_result = step_result.and_then(|output| {
+                if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                    Err(timed_out(started_at).after_output(&output))
+                } else {
+                    Ok(output)
+                }
+            });
+            match step_result {
+                Ok(output) => command_log.push_str(&output),
+                Err(StepError::Failed(output)) | Err(StepError::TimedOut(output)) => {
+                    return result
+                        .with_command(commands.join("\n"))
+                        .with_command_log(command_log + &output)
+                        .failed();
+                }
+                Err(StepError::Interrupted(output)) => {
+                    return result
+                        .with_command(commands.join("\n"))
+                        .with_command_log(command_log + &output)
+                        .interrupted();
+                }
+            }
+        }
+        trace!("Result stdout: {command_log}");
+        let result = result
+            .with_command(commands.join("\n"))
+            .with_command_log(command_log)
+            .succeeded();
+        if self.pass_through {
+            return result.passed_through();
+        }
+        result
+    }
+
+    /// Extracts each JPEG preview embedded in a RAW file with exiftool, then keeps the largest.
+    fn run_preview_extraction(
+        &self,
+        from: &Path,
+        to: &Path,
+        min_size: u32,
+        started_at: Instant,
+    ) -> Result<String, StepError> {
+        let mut previews = vec![];
+        for tag in PREVIEW_TAGS {
+            previews.push(preview_path(to, tag));
+            match self.run_command_line(&preview_command_line(from, to, tag), started_at) {
+                // exiftool fails on the tags missing from the file, others may hold a preview.
+                Ok(_) | Err(StepError::Failed(_)) => {}
+                Err(e) => {
+                    for preview in previews.iter() {
+                        remove_temporary_output(preview);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        keep_largest_preview(&previews, to, min_size)
+            .map(|(width, height)| {
+                format!(
+                    "Extracted the {width}x{height} preview embedded in {}\n",
+                    from.display()
+                )
+            })
+            .map_err(|e| {
+                StepError::Failed(format!(
+                    "Failure extracting the preview embedded in {}: {e}",
+                    from.display()
+                ))
+            })
+    }
+
+    fn run_command_line(
+        &self,
+        command_line: &CommandLine,
+        started_at: Instant,
+    ) -> Result<String, StepError> {
+        // Output redirected to a file leaves only the errors for the command log.
+        let (stdout, stderr) = match &command_line.stdout {
+            Some(path) => {
+                let file = std::fs::File::create(path).map_err(|e| {
+                    StepError::Failed(format!("Failure creating {}: {e}", path.display()))
+                })?;
+                (Redirection::File(file), Redirection::Pipe)
+            }
+            None => (Redirection::Pipe, Redirection::Merge),
+        };
+        let mut process = Popen::create(
+            &command_line.argv(),
+            PopenConfig {
+                cwd: Some(self.cwd.clone().into()),
+                stdout,
+                stderr,
+                // The program runs in its own process group, so it can be killed along with the
+                // processes it spawns.
+                setpgid: true,
+                ..Default::default()
+            },
+        )
+        .map_err(|e| {
+            StepError::Failed(format!("Failure starting {}: {e}", command_line.program))
+        })?;
+        let output = process.stdout.take().or_else(|| process.stderr.take());
+        let stdout_reader = output.map(|mut stdout| {
+            std::thread::spawn(move || {
+                let mut buffer = vec![];
+                let _ = stdout.read_to_end(&mut buffer);
+                String::from_utf8_lossy(&buffer).into_owned()
+            })
+        });
+
+        let exit_status = loop {
+            match process.wait_timeout(Duration::from_millis(500)) {
+                Ok(Some(exit_status)) => break Ok(exit_status),
+                Ok(None) if self.shutdown.is_killing() => {
+                    kill_process_group(&mut process);
+                    break Err(StepError::Interrupted(
+                        "Killed because fixmylib is shutting down".to_owned(),
+                    ));
+                }
+                Ok(None) if self.timeout.is_some_and(|t| started_at.elapsed() > t) => {
+                    kill_process_group(&mut process);
+                    break Err(StepError::TimedOut(format!(
+                        "Timed out after {} seconds, the conversion was killed",
+                        started_at.elapsed().as_secs()
+                    )));
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    kill_process_group(&mut process);
+                    break Err(StepError::Failed(format!(
+                        "Failure waiting for {}: {e}",
+                        command_line.program
+                    )));
+                }
+            }
+        };
+        let stdout = stdout_reader
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default();
+        match exit_status {
+            Ok(ExitStatus::Exited(0)) => Ok(stdout),
+            Ok(exit_status) => Err(StepError::Failed(format!(
+                "{} exited with {exit_status:?}",
+                command_line.program
+            ))
+            .after_output(&stdout)),
+            Err(e) => Err(e.after_output(&stdout)),
+        }
+    }
+}
+
+fn timed_out(started_at: Instant) -> StepError {
+    StepError::TimedOut(format!(
+        "Timed out after {} seconds",
+        started_at.elapsed().as_secs()
+    ))
+}
+
+fn copy_file_modification_date(from: &Path, to: &Path) -> std::io::Result<()> {
+    let metadata = std::fs::metadata(from)?;
+    filetime::set_file_times(
+        to,
+        FileTime::from_last_access_time(&metadata),
+        FileTime::from_last_modification_time(&metadata),
+    )
+}
+
+fn kill_process_group(process: &mut Popen) {
+    if let Some(pid) = process.pid() {
+        // SAFETY: kill has no memory safety requirements, a negative pid targets the group.
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+    }
+    let _ = process.wait();
+}
@@ -1,8 +1,18 @@
-use clap::{arg, Parser};
+use crate::audio_converter::AudioCodec;
+use crate::duplicates::DuplicatesArgs;
+use crate::image_converter::ImageMetadata;
+use crate::requeue::RequeueArgs;
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short, long, env)]
     pub database_url: String,
 
@@ -24,6 +34,13 @@ pub struct Config {
     #[arg(long, env)]
     pub video_converter_threads: usize,
 
+    #[arg(long, env, default_value_t = 2)]
+    pub audio_converter_threads: usize,
+
+    /// Concurrency of the copies of the original preset, bound by the disks.
+    #[arg(long, env, default_value_t = 2)]
+    pub original_converter_threads: usize,
+
     #[arg(long, env)]
     pub seconds_between_file_scans: u64,
 
@@ -35,4 +52,154 @@ pub struct Config {
 
     #[arg(long, env)]
     pub enable_preview_preset: bool,
+
+    #[arg(long, env)]
+    pub enable_original_preset: bool,
+
+    #[arg(long, env)]
+    pub enable_live_preset: bool,
+
+    /// Writes a JPEG of a frame next to each video output, as `<name>.poster.jpg`.
+    #[arg(long, env)]
+    pub enable_video_poster_frames: bool,
+
+    #[arg(long, env, default_value_t = 3)]
+    pub max_job_attempts: i32,
+
+    #[arg(long, env, default_value_t = 60)]
+    pub seconds_between_job_retries: u64,
+
+    #[arg(long, env, default_value_t = 60)]
+    pub seconds_shutdown_grace_period: u64,
+
+    #[arg(long, env, default_value_t = 120)]
+    pub seconds_image_conversion_timeout: u64,
+
+    #[arg(long, env, default_value_t = 2.0)]
+    pub seconds_image_conversion_timeout_per_megapixel: f64,
+
+    #[arg(long, env, default_value_t = 600)]
+    pub seconds_video_conversion_timeout: u64,
+
+    #[arg(long, env, default_value_t = 4.0)]
+    pub seconds_video_conversion_timeout_per_second: f64,
+
+    #[arg(long, env, value_delimiter = ',')]
+    pub preset_timeout_multipliers: Vec<String>,
+
+    #[arg(long, env, value_delimiter = ',')]
+    pub pinned_folders: Vec<String>,
+
+    #[arg(long, env, value_delimiter = ',')]
+    pub media_type_priority: Vec<String>,
+
+    /// Serves the HTTP API and the metrics on `api_address`.
+    #[arg(long, env)]
+    pub enable_api: bool,
+
+    #[arg(long, env, default_value = "127.0.0.1:8080")]
+    pub api_address: SocketAddr,
+
+    /// Token the `/api` routes require as `Authorization: Bearer <token>`. Without one, they are
+    /// only served on a loopback `api_address`.
+    #[arg(long, env)]
+    pub api_token: Option<String>,
+
+    /// Largest number of differing bits between the perceptual hashes of near-duplicate photos,
+    /// out of 64.
+    #[arg(long, env, default_value_t = 10)]
+    pub near_duplicate_max_distance: u32,
+
+    /// Tags copied from the source into the image outputs.
+    #[arg(long, env, value_enum, default_value_t = ImageMetadata::All)]
+    pub image_metadata: ImageMetadata,
+
+    /// Color space of the image outputs of each preset, `srgb` or `p3`.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_color_spaces: Vec<String>,
+
+    /// JPEG quality of the image outputs of each preset, from 1 to 100.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_qualities: Vec<String>,
+
+    /// Chroma subsampling of the image outputs of each preset, `420`, `422` or `444`.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_chroma_subsamplings: Vec<String>,
+
+    /// Whether the image outputs of each preset are progressive JPEGs.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_progressive: Vec<String>,
+
+    /// Whether the image outputs of each preset leave out the metadata.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_strip_metadata: Vec<String>,
+
+    /// Largest image output of each preset in KB.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_max_sizes_kb: Vec<String>,
+
+    /// Crop mode of the image outputs and video poster frames of each preset, `centre`,
+    /// `entropy` or `attention`. Presets not listed are not cropped.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_crops: Vec<String>,
+
+    /// Aspect ratio of the cropped outputs of each preset, e.g. `4:3`, square by default.
+    #[arg(long, env, value_delimiter = ',')]
+    pub image_crop_aspect_ratios: Vec<String>,
+
+    #[arg(long, env, value_enum, default_value_t = AudioCodec::Aac)]
+    pub audio_codec: AudioCodec,
+
+    /// Audio bitrate of each preset in kbit/s.
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "thumbnail=64,preview=128"
+    )]
+    pub audio_bitrates: Vec<String>,
+
+    /// Output format of animated images for each preset, `webp` or `mp4`.
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "thumbnail=webp,preview=mp4"
+    )]
+    pub animated_image_formats: Vec<String>,
+}
+
+/// Parses settings made of `preset=value` entries, ignoring the invalid ones.
+pub fn parse_preset_values<T>(setting: &str, entries: &[String]) -> HashMap<String, T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    entries
+        .iter()
+        .filter(|entry| !entry.trim().is_empty())
+        .flat_map(|entry| match entry.split_once('=') {
+            Some((preset_name, value)) => match value.trim().parse::<T>() {
+                Ok(value) => Some((preset_name.trim().to_owned(), value)),
+                Err(e) => {
+                    error!("Ignoring invalid {setting} entry {entry}: {e}");
+                    None
+                }
+            },
+            None => {
+                error!("Ignoring {setting} entry {entry}, expected preset=value");
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Reset the selected file jobs so they are processed again on the next processor run.
+    Requeue(RequeueArgs),
+    /// Scan the input folder and report what would be converted, without converting anything.
+    Plan,
+    /// Group the near-duplicate photos and write them to `near_duplicates.csv` in the logs folder.
+    Duplicates(DuplicatesArgs),
 }
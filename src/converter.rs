@@ -0,0 +1,347 @@
+use crate::animated_image_converter::AnimatedImageConverter;
+use crate::audio_converter::AudioConverter;
+use crate::command_runner::CommandRunner;
+use crate::image_converter::ImageConverter;
+use crate::metrics::Metrics;
+use crate::original_converter::OriginalConverter;
+use crate::processor::{remove_temporary_output, FileToBeProcessed, ProcessingResult};
+use crate::time::now;
+use crate::video_converter::VideoConverter;
+use crate::AppContext;
+use rayon::iter::ParallelIterator;
+use rayon::prelude::IntoParallelIterator;
+use rayon::ThreadPool;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Handles the conversion of one kind of media. Implementations only describe the conversion,
+/// running the commands and moving the output in place is done by the registry.
+pub trait Converter: Send + Sync {
+    /// Short name used in logs, e.g. "image".
+    fn name(&self) -> &'static str;
+
+    /// Whether this converter handles the file, usually decided from its MIME type.
+    fn handles(&self, file: &FileToBeProcessed) -> bool;
+
+    /// Extension of the output file, without the dot.
+    fn output_extension(&self, file: &FileToBeProcessed) -> String;
+
+    /// Alternative ways of writing the output, named after their backend, tried in order until
+    /// one succeeds.
+    fn commands(&self, file: &FileToBeProcessed, output: &Path) -> Vec<(&'static str, CommandRunner)>;
+
+    /// Secondary outputs derived from the file, written once after the main one succeeded instead
+    /// of by each of its backends.
+    fn extra_outputs(&self, _file: &FileToBeProcessed) -> Vec<ExtraOutput> {
+        vec![]
+    }
+
+    /// Extracts metrics or other details from a finished conversion.
+    fn parse_result(&self, _file: &FileToBeProcessed, result: ProcessingResult) -> ProcessingResult {
+        result
+    }
+
+    /// Expected size in bytes of the output, used by the plan.
+    fn estimate_output_size(&self, _file: &FileToBeProcessed) -> Option<u64> {
+        None
+    }
+
+    /// Expected time of the conversion on a single thread, used by the plan.
+    fn estimate_duration(
+        &self,
+        _file: &FileToBeProcessed,
+        _throughput: &Throughput,
+    ) -> Option<Duration> {
+        None
+    }
+}
+
+/// Secondary output of a conversion, like the video embedded in a motion photo. Its failure is logged
+/// without failing the conversion.
+pub struct ExtraOutput {
+    /// What the output is, for the logs, e.g. "motion photo video".
+    pub name: &'static str,
+    pub path: PathBuf,
+    /// Hidden file written by the command, renamed to `path` along with the main output.
+    pub temporary_path: PathBuf,
+    pub command: CommandRunner,
+}
+
+/// Conversion speed on a single thread, measured on past jobs or assumed when there are none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Throughput {
+    pub megapixels_per_second: f64,
+    pub fps: f64,
+}
+
+struct RegisteredConverter {
+    converter: Box<dyn Converter>,
+    pool: ThreadPool,
+    metrics: Arc<Metrics>,
+}
+
+impl RegisteredConverter {
+    fn convert_files<'a>(
+        &self,
+        files: Vec<FileToBeProcessed<'a>>,
+    ) -> Vec<(FileToBeProcessed<'a>, ProcessingResult)> {
+        self.pool.install(|| {
+            files
+                .into_par_iter()
+                .map(|file| {
+                    let result = self.convert(&file);
+                    (file, result)
+                })
+                .collect()
+        })
+    }
+
+    fn convert(&self, file: &FileToBeProcessed) -> ProcessingResult {
+        let started_at = now();
+        let extension = self.converter.output_extension(file);
+        let output = file.temporary_output_path(&extension);
+        let extra_outputs = self.converter.extra_outputs(file);
+        for extra_output in extra_outputs.iter() {
+            remove_temporary_output(&extra_output.temporary_path);
+        }
+        let (backend, result) = run_backends(
+            self.converter.name(),
+            file.file_full_path(),
+            self.converter.commands(file, &output),
+            &output,
+        );
+        let (result, extra_outputs) = write_extra_outputs(file, result, extra_outputs);
+        let result = file.finalize_output(&extension, result);
+        let result = finalize_extra_outputs(result, extra_outputs)
+            .with_processing_started_at(started_at)
+            .with_backend(backend);
+        let result = self.converter.parse_result(file, result);
+        self.metrics
+            .record_conversion(self.converter.name(), backend, &result);
+        result
+    }
+}
+
+/// Runs the backends in order until one succeeds or is interrupted, returning the last one run
+/// with its result. The output is removed before each of them: it may be left by a crashed run or
+/// by a failed backend, and may even be a link to the source after a pass-through, which the next
+/// backend would otherwise write through.
+fn run_backends(
+    converter_name: &str,
+    file_full_path: &str,
+    commands: Vec<(&'static str, CommandRunner)>,
+    output: &Path,
+) -> (&'static str, ProcessingResult) {
+    let mut result = ProcessingResult::new()
+        .with_command_log(format!("No {converter_name} conversion command"))
+        .failed();
+    let mut backend = "none";
+    // The backends share the timeout, so falling back cannot take longer than a single one.
+    let started_at = Instant::now();
+    for (i, (command_backend, command)) in commands.into_iter().enumerate() {
+        if i > 0 {
+            info!(
+                "{converter_name} conversion for {file_full_path} has failed, falling back to the next method..."
+            );
+        }
+        remove_temporary_output(output);
+        backend = command_backend;
+        result = command.run_since(started_at);
+        if result.has_succeeded || result.interrupted {
+            break;
+        }
+    }
+    (backend, result)
+}
+
+/// Writes the extra outputs of a successful conversion to their temporary path, returning the ones
+/// written. The others are only reported in the command log.
+fn write_extra_outputs(
+    file: &FileToBeProcessed,
+    mut result: ProcessingResult,
+    extra_outputs: Vec<ExtraOutput>,
+) -> (ProcessingResult, Vec<ExtraOutput>) {
+    if !result.has_succeeded {
+        return (result, vec![]);
+    }
+    let mut written = vec![];
+    for extra_output in extra_outputs {
+        let extra_result = extra_output.command.run();
+        if extra_result.has_succeeded {
+            written.push(extra_output);
+            continue;
+        }
+        error!(
+            "Failure writing the {} of {}, the conversion is kept without it",
+            extra_output.name,
+            file.file_full_path()
+        );
+        result.command_log = format!(
+            "{}\nFailure writing the {}:\n{}",
+            result.command_log, extra_output.name, extra_result.command_log
+        );
+        remove_temporary_output(&extra_output.temporary_path);
+    }
+    (result, written)
+}
+
+/// Moves the written extra outputs in place along with the main output, or removes them when it
+/// could not be.
+fn finalize_extra_outputs(
+    mut result: ProcessingResult,
+    extra_outputs: Vec<ExtraOutput>,
+) -> ProcessingResult {
+    for extra_output in extra_outputs {
+        if !result.has_succeeded {
+            remove_temporary_output(&extra_output.temporary_path);
+            continue;
+        }
+        match std::fs::rename(&extra_output.temporary_path, &extra_output.path) {
+            Ok(()) => result = result.with_extra_output(extra_output.path),
+            Err(e) => {
+                error!(
+                    "Failure moving {} to {}: {e}",
+                    extra_output.temporary_path.display(),
+                    extra_output.path.display()
+                );
+                remove_temporary_output(&extra_output.temporary_path);
+            }
+        }
+    }
+    result
+}
+
+/// Converters known to the processor, each with its own thread pool. A file is handled by the
+/// first registered converter accepting it.
+pub struct ConverterRegistry {
+    converters: Vec<RegisteredConverter>,
+    metrics: Arc<Metrics>,
+}
+
+impl ConverterRegistry {
+    pub fn new(ctx: &AppContext) -> ConverterRegistry {
+        ConverterRegistry {
+            converters: vec![],
+            metrics: ctx.metrics.clone(),
+        }
+            .register(OriginalConverter, ctx.config.original_converter_threads)
+            .register(AnimatedImageConverter::new(ctx), ctx.config.image_converter_threads)
+            .register(ImageConverter::new(ctx), ctx.config.image_converter_threads)
+            .register(VideoConverter::new(ctx), ctx.config.video_converter_threads)
+            .register(AudioConverter::new(ctx), ctx.config.audio_converter_threads)
+    }
+
+    pub fn register(mut self, converter: impl Converter + 'static, threads: usize) -> ConverterRegistry {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap_or_else(|e| {
+                panic!("Failure initing threadpool for {} processing: {e}", converter.name())
+            });
+        self.converters.push(RegisteredConverter {
+            converter: Box::new(converter),
+            pool,
+            metrics: self.metrics.clone(),
+        });
+        self
+    }
+
+    /// Converter of the file, with the number of threads it runs on.
+    pub fn find(&self, file: &FileToBeProcessed) -> Option<(&dyn Converter, usize)> {
+        self.converters
+            .iter()
+            .find(|c| c.converter.handles(file))
+            .map(|c| (c.converter.as_ref(), c.pool.current_num_threads()))
+    }
+
+    /// Converts every file with its converter. Files no converter handles are left as is and
+    /// marked as succeeded.
+    pub fn convert_files<'a>(
+        &self,
+        files: Vec<FileToBeProcessed<'a>>,
+    ) -> Vec<(FileToBeProcessed<'a>, ProcessingResult)> {
+        let files_count = files.len();
+        let mut files_by_converter: Vec<Vec<FileToBeProcessed>> =
+            self.converters.iter().map(|_| vec![]).collect();
+        let mut unhandled_files = vec![];
+        for file in files {
+            match self.converters.iter().position(|c| c.converter.handles(&file)) {
+                Some(i) => files_by_converter[i].push(file),
+                None => unhandled_files.push(file),
+            }
+        }
+
+        let found: Vec<String> = self
+            .converters
+            .iter()
+            .zip(files_by_converter.iter())
+            .map(|(c, files)| format!("{} {} files", files.len(), c.converter.name()))
+            .collect();
+        info!(
+            "Found {}, and {} files without converter out of {} files.",
+            found.join(", "),
+            unhandled_files.len(),
+            files_count
+        );
+
+        let mut results = vec![];
+        for (converter, files) in self.converters.iter().zip(files_by_converter) {
+            if files.is_empty() {
+                continue;
+            }
+            info!("Processing {} files...", converter.converter.name());
+            results.extend(converter.convert_files(files));
+        }
+        results.extend(unhandled_files.into_iter().map(|file| {
+            let result = ProcessingResult::new()
+                .with_command_log(format!(
+                    "No converter handles this file. Mime type: {}",
+                    file.exif.mime_type
+                ))
+                .succeeded();
+            (file, result)
+        }));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::CommandLine;
+    use crate::shutdown::Shutdown;
+
+    #[test]
+    fn failed_pass_through_leaves_the_source_unchanged() {
+        let folder = std::env::temp_dir().join(format!("fixmylib-converter-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let source = folder.join("source.jpg");
+        let output = folder.join(".source.fixmylib-tmp.jpg");
+        std::fs::write(&source, "source").unwrap();
+        let shutdown = Shutdown::default();
+        let cwd = folder.to_str().unwrap();
+        let commands = vec![
+            (
+                "passthrough",
+                CommandRunner::build(cwd, &shutdown)
+                    .link_or_copy(&source, &output)
+                    .with(CommandLine::new("false")),
+            ),
+            (
+                "convert",
+                CommandRunner::build(cwd, &shutdown).with(
+                    CommandLine::new("sh").args(["-c", "echo converted > .source.fixmylib-tmp.jpg"]),
+                ),
+            ),
+        ];
+
+        let (backend, result) = run_backends("image", "source.jpg", commands, &output);
+
+        assert!(result.has_succeeded);
+        assert_eq!(backend, "convert");
+        assert_eq!(std::fs::read_to_string(&source).unwrap(), "source");
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "converted\n");
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+use crate::config::{parse_preset_values, Config};
+use image::RgbImage;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Used for cropped presets missing from the crop aspect ratios setting.
+const DEFAULT_ASPECT_RATIO: AspectRatio = AspectRatio {
+    width: 1,
+    height: 1,
+};
+/// Lines trimmed at once by the entropy crop.
+const ENTROPY_SLICE: u32 = 8;
+
+/// How the part of the image kept by a crop is chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropMode {
+    /// The middle of the image.
+    Centre,
+    /// The most detailed part, trimming the edge with the least entropy until the image fits.
+    Entropy,
+    /// The part with the most edges and saturated colors, which draw the eye, without looking
+    /// for faces.
+    Attention,
+}
+
+impl FromStr for CropMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "centre" | "center" => Ok(CropMode::Centre),
+            "entropy" => Ok(CropMode::Entropy),
+            "attention" | "saliency" => Ok(CropMode::Attention),
+            _ => Err(format!(
+                "unknown crop mode {s}, expected centre, entropy or attention"
+            )),
+        }
+    }
+}
+
+impl Display for CropMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CropMode::Centre => "centre",
+            CropMode::Entropy => "entropy",
+            CropMode::Attention => "attention",
+        })
+    }
+}
+
+/// Shape of the cropped outputs, e.g. `4:3` for landscape ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AspectRatio {
+    width: u32,
+    height: u32,
+}
+
+impl FromStr for AspectRatio {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid aspect ratio {s}, expected width:height");
+        let (width, height) = s.split_once(':').ok_or_else(invalid)?;
+        let width = width.trim().parse().map_err(|_| invalid())?;
+        let height = height.trim().parse().map_err(|_| invalid())?;
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+        Ok(AspectRatio { width, height })
+    }
+}
+
+impl Display for AspectRatio {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.width, self.height)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub mode: CropMode,
+    pub aspect_ratio: AspectRatio,
+}
+
+impl Crop {
+    /// Dimensions of the output, whose smallest side is `size`.
+    pub fn dimensions(&self, size: u32) -> (u32, u32) {
+        let AspectRatio { width, height } = self.aspect_ratio;
+        let scale = |side: u32, other: u32| (size as u64 * side as u64 / other as u64) as u32;
+        if width >= height {
+            (scale(width, height), size)
+        } else {
+            (size, scale(height, width))
+        }
+    }
+}
+
+/// The crop settings of all the presets.
+pub struct Crops {
+    modes: HashMap<String, CropMode>,
+    aspect_ratios: HashMap<String, AspectRatio>,
+}
+
+impl Crops {
+    pub fn new(config: &Config) -> Crops {
+        Crops {
+            modes: parse_preset_values("IMAGE_CROPS", &config.image_crops),
+            aspect_ratios: parse_preset_values(
+                "IMAGE_CROP_ASPECT_RATIOS",
+                &config.image_crop_aspect_ratios,
+            ),
+        }
+    }
+
+    /// The crop of the preset, if its outputs are cropped.
+    pub fn preset(&self, preset_name: &str) -> Option<Crop> {
+        let mode = *self.modes.get(preset_name)?;
+        let aspect_ratio = self
+            .aspect_ratios
+            .get(preset_name)
+            .copied()
+            .unwrap_or(DEFAULT_ASPECT_RATIO);
+        Some(Crop { mode, aspect_ratio })
+    }
+}
+
+/// Top left corner of the `width` x `height` part of the image to keep.
+pub fn crop_position(image: &RgbImage, width: u32, height: u32, mode: CropMode) -> (u32, u32) {
+    let (image_width, image_height) = image.dimensions();
+    let (width, height) = (width.min(image_width), height.min(image_height));
+    match mode {
+        CropMode::Centre => ((image_width - width) / 2, (image_height - height) / 2),
+        CropMode::Entropy => {
+            let luma = Luma::new(image);
+            (
+                trim_by_entropy(image_width, width, |columns| {
+                    luma.entropy(columns, 0..image_height)
+                }),
+                trim_by_entropy(image_height, height, |rows| {
+                    luma.entropy(0..image_width, rows)
+                }),
+            )
+        }
+        CropMode::Attention => {
+            let saliency = saliency(image);
+            let column_scores = (0..image_width)
+                .map(|x| {
+                    (0..image_height)
+                        .map(|y| saliency[(y * image_width + x) as usize])
+                        .sum()
+                })
+                .collect::<Vec<u64>>();
+            let row_scores = (0..image_height)
+                .map(|y| {
+                    let row = (y * image_width) as usize..((y + 1) * image_width) as usize;
+                    saliency[row].iter().sum()
+                })
+                .collect::<Vec<u64>>();
+            (
+                most_salient_window(&column_scores, width),
+                most_salient_window(&row_scores, height),
+            )
+        }
+    }
+}
+
+/// Brightness of the pixels, row by row.
+struct Luma {
+    width: u32,
+    pixels: Vec<u8>,
+}
+
+impl Luma {
+    fn new(image: &RgbImage) -> Luma {
+        let pixels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+            })
+            .collect();
+        Luma {
+            width: image.width(),
+            pixels,
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> u8 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Shannon entropy of the brightness histogram of a part of the image.
+    fn entropy(&self, columns: Range<u32>, rows: Range<u32>) -> f64 {
+        let mut histogram = [0u64; 256];
+        for y in rows {
+            for x in columns.clone() {
+                histogram[self.get(x, y) as usize] += 1;
+            }
+        }
+        let total = histogram.iter().sum::<u64>() as f64;
+        histogram
+            .iter()
+            .filter(|&&count| count != 0)
+            .map(|&count| {
+                let probability = count as f64 / total;
+                -probability * probability.log2()
+            })
+            .sum()
+    }
+}
+
+/// Start of the window of `length` lines left after trimming, from either end of `0..total`, the
+/// slice with the least entropy.
+fn trim_by_entropy(total: u32, length: u32, entropy: impl Fn(Range<u32>) -> f64) -> u32 {
+    let (mut start, mut end) = (0, total);
+    while end - start > length {
+        let slice = (end - start - length).min(ENTROPY_SLICE);
+        if entropy(start..start + slice) < entropy(end - slice..end) {
+            start += slice;
+        } else {
+            end -= slice;
+        }
+    }
+    start
+}
+
+/// Saliency of each pixel, row by row: the edges found by a Laplacian filter on the brightness,
+/// plus half the saturation.
+fn saliency(image: &RgbImage) -> Vec<u64> {
+    let luma = Luma::new(image);
+    let (width, height) = image.dimensions();
+    image
+        .enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            let center = luma.get(x, y) as i64;
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            let edge = neighbours
+                .into_iter()
+                .filter(|&(x, y)| x < width && y < height)
+                .map(|(x, y)| center - luma.get(x, y) as i64)
+                .sum::<i64>()
+                .unsigned_abs();
+            let [r, g, b] = pixel.0;
+            let saturation = (r.max(g).max(b) - r.min(g).min(b)) as u64;
+            edge + saturation / 2
+        })
+        .collect()
+}
+
+/// Start of the window of `length` lines with the highest total score, the most central one
+/// when several are equal.
+fn most_salient_window(scores: &[u64], length: u32) -> u32 {
+    let length = length as usize;
+    let windows = scores.len() - length + 1;
+    let mut sum = scores[..length].iter().sum::<u64>();
+    let mut best = (sum, 0usize);
+    let centre = (windows - 1) / 2;
+    for start in 1..windows {
+        sum = sum + scores[start + length - 1] - scores[start - 1];
+        let is_closer = start.abs_diff(centre) < best.1.abs_diff(centre);
+        if sum > best.0 || (sum == best.0 && is_closer) {
+            best = (sum, start);
+        }
+    }
+    best.1 as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const MODES: [CropMode; 3] = [CropMode::Centre, CropMode::Entropy, CropMode::Attention];
+
+    fn crop(mode: CropMode, aspect_ratio: &str) -> Crop {
+        Crop {
+            mode,
+            aspect_ratio: aspect_ratio.parse().unwrap(),
+        }
+    }
+
+    /// Flat gray, with a detailed and colorful square whose top left corner is at `x`, `y`.
+    fn image_with_detail(width: u32, height: u32, x: u32, y: u32, side: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |px, py| {
+            let is_detail = (x..x + side).contains(&px) && (y..y + side).contains(&py);
+            if is_detail {
+                let value = ((px * 37 + py * 91) % 256) as u8;
+                Rgb([value, 255 - value, 0])
+            } else {
+                Rgb([128, 128, 128])
+            }
+        })
+    }
+
+    #[test]
+    fn dimensions_keep_the_smallest_side_at_the_size() {
+        assert_eq!(crop(CropMode::Centre, "1:1").dimensions(400), (400, 400));
+        assert_eq!(crop(CropMode::Centre, "4:3").dimensions(300), (400, 300));
+        assert_eq!(crop(CropMode::Centre, "9:16").dimensions(900), (900, 1600));
+    }
+
+    #[test]
+    fn crop_window_larger_than_the_image_keeps_all_of_it() {
+        let image = image_with_detail(10, 6, 2, 2, 3);
+        for mode in MODES {
+            assert_eq!(crop_position(&image, 20, 20, mode), (0, 0), "{mode}");
+            assert_eq!(crop_position(&image, 20, 4, mode).0, 0, "{mode}");
+            assert_eq!(crop_position(&image, 4, 20, mode).1, 0, "{mode}");
+        }
+    }
+
+    #[test]
+    fn centre_crop_keeps_the_middle() {
+        let image = image_with_detail(10, 6, 0, 0, 1);
+        assert_eq!(crop_position(&image, 4, 4, CropMode::Centre), (3, 1));
+        assert_eq!(crop_position(&image, 5, 6, CropMode::Centre), (2, 0));
+    }
+
+    #[test]
+    fn entropy_and_attention_crops_keep_the_detailed_part() {
+        let image = image_with_detail(64, 32, 40, 8, 16);
+        for mode in [CropMode::Entropy, CropMode::Attention] {
+            let (x, y) = crop_position(&image, 24, 24, mode);
+            assert!((24..=40).contains(&x), "{mode} kept x {x}");
+            assert!(y <= 8, "{mode} kept y {y}");
+        }
+    }
+
+    #[test]
+    fn most_salient_window_prefers_the_highest_scores() {
+        assert_eq!(most_salient_window(&[0, 0, 0, 5, 9, 1], 2), 3);
+        assert_eq!(most_salient_window(&[9, 0, 0, 0, 0, 0], 3), 0);
+        assert_eq!(most_salient_window(&[1, 2, 3], 3), 0);
+    }
+
+    #[test]
+    fn most_salient_window_is_central_among_equal_ones() {
+        assert_eq!(most_salient_window(&[1; 9], 3), 3);
+        assert_eq!(most_salient_window(&[0; 4], 1), 1);
+    }
+
+    #[test]
+    fn aspect_ratios_are_parsed_and_displayed_as_width_and_height() {
+        let aspect_ratio: AspectRatio = " 16 : 9 ".parse().unwrap();
+        assert_eq!(aspect_ratio.to_string(), "16:9");
+        for invalid in ["16", "16:0", "0:9", "a:b", "16:9:1", ""] {
+            assert!(invalid.parse::<AspectRatio>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn crop_modes_are_parsed_with_their_aliases() {
+        assert_eq!("center".parse(), Ok(CropMode::Centre));
+        assert_eq!("Entropy".parse(), Ok(CropMode::Entropy));
+        assert_eq!("saliency".parse(), Ok(CropMode::Attention));
+        assert!("faces".parse::<CropMode>().is_err());
+        for mode in MODES {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+    }
+}
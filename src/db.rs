@@ -1,9 +1,12 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 
 use sqlx::types::time::PrimitiveDateTime;
 use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::time::now;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct File {
     pub file_full_path: String,
@@ -18,6 +21,9 @@ pub struct File {
     pub updated_at: PrimitiveDateTime,
     pub file_modified_at: PrimitiveDateTime,
     pub filescan_job_id: Uuid,
+    pub mime_type: Option<String>,
+    /// Capture date read by exiftool, once the file has been processed.
+    pub captured_at: Option<PrimitiveDateTime>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -46,6 +52,19 @@ pub struct FileJob {
     pub command: Option<String>,
     pub command_log: Option<String>,
     pub has_succeeded: Option<bool>,
+    pub attempts: i32,
+    pub next_attempt_at: Option<PrimitiveDateTime>,
+    pub priority: i64,
+    /// Wall time of the successful conversion.
+    pub processing_seconds: Option<f64>,
+    /// Megapixels of the converted image.
+    pub megapixels: Option<f64>,
+    /// Mean transcoding frames per second of the converted video.
+    pub fps: Option<i32>,
+    /// The output is the source itself, copied or remuxed instead of transcoded.
+    pub pass_through: bool,
+    /// Conversion command that wrote the output, e.g. `native` or `imagemagick` for images.
+    pub backend: Option<String>,
 }
 
 pub async fn get_unfinished_filescan_jobs(db: &Pool<Postgres>) -> Result<Vec<FilescanJob>> {
@@ -89,10 +108,11 @@ pub async fn upsert_file_job(db: &Pool<Postgres>, job: FileJob) -> Result<FileJo
         FileJob,
         r#"
         with file_job_upsert as (
-        insert into file_jobs (file_full_path, preset_name, created_at, finished_at) values ($1, $2, $3, $4)
+        insert into file_jobs (file_full_path, preset_name, created_at, finished_at, priority) values ($1, $2, $3, $4, $5)
         on conflict(file_full_path, preset_name) do update set
             created_at = excluded.created_at,
-            finished_at = excluded.finished_at
+            finished_at = excluded.finished_at,
+            priority = excluded.priority
             returning *
         )
         select * from file_job_upsert where file_full_path = $1 and preset_name = $2
@@ -100,7 +120,8 @@ pub async fn upsert_file_job(db: &Pool<Postgres>, job: FileJob) -> Result<FileJo
         job.file_full_path,
         job.preset_name,
         job.created_at,
-        job.finished_at
+        job.finished_at,
+        job.priority
     )
         .fetch_one(db)
         .await?;
@@ -122,11 +143,20 @@ pub async fn upsert_file_jobs(db: &Pool<Postgres>, file_jobs: Vec<FileJob>) -> R
         file_jobs.iter().map(|f| f.command_log.clone()).collect();
     let has_succeeded_values: Vec<Option<bool>> =
         file_jobs.iter().map(|f| f.has_succeeded).collect();
+    let attempts_values: Vec<i32> = file_jobs.iter().map(|f| f.attempts).collect();
+    let next_attempt_at_values: Vec<Option<PrimitiveDateTime>> =
+        file_jobs.iter().map(|f| f.next_attempt_at).collect();
+    let processing_seconds_values: Vec<Option<f64>> =
+        file_jobs.iter().map(|f| f.processing_seconds).collect();
+    let megapixels_values: Vec<Option<f64>> = file_jobs.iter().map(|f| f.megapixels).collect();
+    let fps_values: Vec<Option<i32>> = file_jobs.iter().map(|f| f.fps).collect();
+    let pass_through_values: Vec<bool> = file_jobs.iter().map(|f| f.pass_through).collect();
+    let backend_values: Vec<Option<String>> = file_jobs.iter().map(|f| f.backend.clone()).collect();
 
     sqlx
     ::query!(
         r#"
-        INSERT INTO file_jobs (file_full_path, preset_name, created_at, finished_at, command, command_log, has_succeeded)
+        INSERT INTO file_jobs (file_full_path, preset_name, created_at, finished_at, command, command_log, has_succeeded, attempts, next_attempt_at, processing_seconds, megapixels, fps, pass_through, backend)
         SELECT
           t.file_full_path::TEXT,
           t.preset_name::TEXT,
@@ -134,14 +164,28 @@ pub async fn upsert_file_jobs(db: &Pool<Postgres>, file_jobs: Vec<FileJob>) -> R
           t.finished_at::TIMESTAMP,
           t.command::TEXT,
           t.command_log::TEXT,
-          t.has_succeeded::BOOL
-        FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TIMESTAMP[], $4::TIMESTAMP[], $5::TEXT[], $6::TEXT[], $7::BOOL[]) AS t (file_full_path, preset_name, created_at, finished_at, command, command_log, has_succeeded)
+          t.has_succeeded::BOOL,
+          t.attempts::INT,
+          t.next_attempt_at::TIMESTAMP,
+          t.processing_seconds::DOUBLE PRECISION,
+          t.megapixels::DOUBLE PRECISION,
+          t.fps::INT,
+          t.pass_through::BOOL,
+          t.backend::TEXT
+        FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TIMESTAMP[], $4::TIMESTAMP[], $5::TEXT[], $6::TEXT[], $7::BOOL[], $8::INT[], $9::TIMESTAMP[], $10::DOUBLE PRECISION[], $11::DOUBLE PRECISION[], $12::INT[], $13::BOOL[], $14::TEXT[]) AS t (file_full_path, preset_name, created_at, finished_at, command, command_log, has_succeeded, attempts, next_attempt_at, processing_seconds, megapixels, fps, pass_through, backend)
         ON CONFLICT (file_full_path, preset_name) DO UPDATE
         SET
           finished_at = EXCLUDED.finished_at,
           command = EXCLUDED.command,
           command_log = EXCLUDED.command_log,
-          has_succeeded = EXCLUDED.has_succeeded;
+          has_succeeded = EXCLUDED.has_succeeded,
+          attempts = EXCLUDED.attempts,
+          next_attempt_at = EXCLUDED.next_attempt_at,
+          processing_seconds = EXCLUDED.processing_seconds,
+          megapixels = EXCLUDED.megapixels,
+          fps = EXCLUDED.fps,
+          pass_through = EXCLUDED.pass_through,
+          backend = EXCLUDED.backend;
         "#,
         &file_full_path_values[..],
         &preset_name_values[..],
@@ -150,6 +194,13 @@ pub async fn upsert_file_jobs(db: &Pool<Postgres>, file_jobs: Vec<FileJob>) -> R
         &command_values[..]: Vec<Option<String>>,
         &command_log_values[..]: Vec<Option<String>> ,
         &has_succeeded_values[..]: Vec<Option<bool>>,
+        &attempts_values[..],
+        &next_attempt_at_values[..]: Vec<Option<PrimitiveDateTime>>,
+        &processing_seconds_values[..]: Vec<Option<f64>>,
+        &megapixels_values[..]: Vec<Option<f64>>,
+        &fps_values[..]: Vec<Option<i32>>,
+        &pass_through_values[..],
+        &backend_values[..]: Vec<Option<String>>,
     )
         .execute(db)
         .await?;
@@ -232,8 +283,10 @@ pub async fn upsert_file(db: &Pool<Postgres>, file: File) -> Result<File> {
             created_at,
             updated_at,
             file_modified_at,
-            filescan_job_id
-            ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            filescan_job_id,
+            mime_type,
+            captured_at
+            ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
             on conflict (file_full_path) DO UPDATE SET
                 file_full_path = excluded.file_full_path,
                 folder_full_path = excluded.folder_full_path,
@@ -246,7 +299,9 @@ pub async fn upsert_file(db: &Pool<Postgres>, file: File) -> Result<File> {
                 created_at = excluded.created_at,
                 updated_at = excluded.updated_at,
                 file_modified_at = excluded.file_modified_at,
-                filescan_job_id = excluded.filescan_job_id
+                filescan_job_id = excluded.filescan_job_id,
+                mime_type = coalesce(excluded.mime_type, files.mime_type),
+                captured_at = coalesce(excluded.captured_at, files.captured_at)
             returning *
         ) select * from file_insert where file_full_path = $1
         "#,
@@ -261,7 +316,9 @@ pub async fn upsert_file(db: &Pool<Postgres>, file: File) -> Result<File> {
         file.created_at,
         file.updated_at,
         file.file_modified_at,
-        file.filescan_job_id
+        file.filescan_job_id,
+        file.mime_type,
+        file.captured_at
     )
         .fetch_one(db)
         .await?;
@@ -288,11 +345,87 @@ pub async fn get_unprocessed_files_for_a_given_preset_name(
             files.created_at as "created_at!",
             files.updated_at as "updated_at!",
             files.file_modified_at as "file_modified_at!",
-            files.filescan_job_id as "filescan_job_id!"
+            files.filescan_job_id as "filescan_job_id!",
+            files.mime_type,
+            files.captured_at
              from files
              LEFT JOIN file_jobs ON files.file_full_path = file_jobs.file_full_path AND file_jobs.preset_name = $1
              WHERE file_jobs.finished_at IS NULL
-             ORDER BY files.folder_full_path
+               AND (file_jobs.next_attempt_at IS NULL OR file_jobs.next_attempt_at <= $4)
+             ORDER BY file_jobs.priority DESC NULLS LAST, files.folder_full_path
+             OFFSET $2 ROWS
+             FETCH NEXT $3 ROWS ONLY
+             "#,
+        preset_name,
+        offset,
+        limit,
+        now()
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(files)
+}
+
+/// Which files a preset converts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresetFiles {
+    /// Only the clips of Live Photos if true, only the other files if false, or both if unset.
+    pub live_photo_clips: Option<bool>,
+    /// Whether videos extracted from motion photos are converted.
+    pub motion_photo_videos: bool,
+    /// Whether the files associated as sidecars of another one are converted.
+    pub sidecars: bool,
+}
+
+pub async fn get_files_without_file_job_for_a_given_preset_name(
+    db: &Pool<Postgres>,
+    preset_name: &str,
+    preset_files: &PresetFiles,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<File>> {
+    let files = sqlx::query_as!(
+        File,
+        r#"SELECT files.* from files
+             LEFT JOIN file_jobs ON files.file_full_path = file_jobs.file_full_path AND file_jobs.preset_name = $1
+             WHERE file_jobs.file_full_path IS NULL
+               AND ($4::BOOL IS NULL OR EXISTS (
+                 SELECT 1 FROM live_photos WHERE live_photos.clip_full_path = files.file_full_path
+               ) = $4)
+               AND ($5 OR NOT EXISTS (
+                 SELECT 1 FROM motion_photos WHERE motion_photos.video_full_path = files.file_full_path
+               ))
+               AND ($6 OR NOT EXISTS (
+                 SELECT 1 FROM sidecars WHERE sidecars.sidecar_full_path = files.file_full_path
+               ))
+             ORDER BY files.file_full_path
+             OFFSET $2 ROWS
+             FETCH NEXT $3 ROWS ONLY
+             "#,
+        preset_name,
+        offset,
+        limit,
+        preset_files.live_photo_clips,
+        preset_files.motion_photo_videos,
+        preset_files.sidecars
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(files)
+}
+
+pub async fn get_files_with_unfinished_file_job_for_a_given_preset_name(
+    db: &Pool<Postgres>,
+    preset_name: &str,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<File>> {
+    let files = sqlx::query_as!(
+        File,
+        r#"SELECT files.* from files
+             JOIN file_jobs ON files.file_full_path = file_jobs.file_full_path AND file_jobs.preset_name = $1
+             WHERE file_jobs.finished_at IS NULL
+             ORDER BY files.file_full_path
              OFFSET $2 ROWS
              FETCH NEXT $3 ROWS ONLY
              "#,
@@ -305,6 +438,29 @@ pub async fn get_unprocessed_files_for_a_given_preset_name(
     Ok(files)
 }
 
+pub async fn update_file_job_priorities(
+    db: &Pool<Postgres>,
+    preset_name: &str,
+    file_priorities: &[(String, i64)],
+) -> Result<()> {
+    let (file_full_path_values, priority_values): (Vec<String>, Vec<i64>) =
+        file_priorities.iter().cloned().unzip();
+
+    sqlx::query!(
+        r#"
+        UPDATE file_jobs SET priority = t.priority
+        FROM UNNEST($2::TEXT[], $3::BIGINT[]) AS t (file_full_path, priority)
+        WHERE file_jobs.preset_name = $1 AND file_jobs.file_full_path = t.file_full_path
+        "#,
+        preset_name,
+        &file_full_path_values[..],
+        &priority_values[..]
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
 pub async fn get_unprocessed_file_jobs_for_a_given_preset_name_and_files(
     db: &Pool<Postgres>,
     preset_name: &str,
@@ -339,10 +495,21 @@ pub async fn get_unprocessed_file_and_jobs(
     limit: i64,
 ) -> Result<Vec<(File, FileJob)>> {
     let files = get_unprocessed_files_for_a_given_preset_name(db, preset_name, offset, limit).await?;
-    let file_jobs =
-        get_unprocessed_file_jobs_for_a_given_preset_name_and_files(db, preset_name, &files).await?;
+    let mut file_jobs: HashMap<String, FileJob> =
+        get_unprocessed_file_jobs_for_a_given_preset_name_and_files(db, preset_name, &files)
+            .await?
+            .into_iter()
+            .map(|job| (job.file_full_path.clone(), job))
+            .collect();
 
-    Ok(files.into_iter().zip(file_jobs.into_iter()).collect())
+    Ok(files
+        .into_iter()
+        .flat_map(|file| {
+            file_jobs
+                .remove(&file.file_full_path)
+                .map(|job| (file, job))
+        })
+        .collect())
 }
 
 
@@ -351,10 +518,593 @@ pub async fn get_failed_file_jobs(
 ) -> Result<Vec<FileJob>> {
     let file_jobs = sqlx::query_as!(
         FileJob,
-        r#"SELECT * from file_jobs where has_succeeded = false
+        r#"SELECT * from file_jobs where has_succeeded = false and finished_at is not null
+        "#,
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(file_jobs)
+}
+
+/// Stores the MIME type and capture date exiftool read from the files.
+pub async fn update_files_metadata(db: &Pool<Postgres>, files: &[File]) -> Result<()> {
+    let files: Vec<&File> = files.iter().filter(|f| f.mime_type.is_some()).collect();
+    let file_full_path_values: Vec<String> =
+        files.iter().map(|f| f.file_full_path.clone()).collect();
+    let mime_type_values: Vec<Option<String>> = files.iter().map(|f| f.mime_type.clone()).collect();
+    let captured_at_values: Vec<Option<PrimitiveDateTime>> =
+        files.iter().map(|f| f.captured_at).collect();
+
+    sqlx::query!(
+        r#"
+        UPDATE files SET mime_type = t.mime_type, captured_at = t.captured_at
+        FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TIMESTAMP[]) AS t (file_full_path, mime_type, captured_at)
+        WHERE files.file_full_path = t.file_full_path
+        "#,
+        &file_full_path_values[..],
+        &mime_type_values[..]: Vec<Option<String>>,
+        &captured_at_values[..]: Vec<Option<PrimitiveDateTime>>
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Selection of file jobs. Unset criteria match every job; path and MIME type patterns are
+/// POSIX regular expressions and the error pattern is matched case insensitively.
+#[derive(Debug, Default, Clone)]
+pub struct FileJobSelection {
+    pub preset_name: Option<String>,
+    pub only_failed: bool,
+    pub path_regex: Option<String>,
+    pub mime_type_regex: Option<String>,
+    /// Extensions whose usual MIME type matches, for the files exiftool has not run on yet.
+    pub mime_type_extensions: Vec<String>,
+    pub error_regex: Option<String>,
+}
+
+/// Postgres SQLSTATE of an invalid regular expression.
+const INVALID_REGULAR_EXPRESSION: &str = "2201B";
+
+/// Compiles the regular expression with Postgres, whose syntax differs from the one of Rust, and
+/// returns why it is invalid, if it is.
+pub async fn check_regex(db: &Pool<Postgres>, regex: &str) -> Result<Option<String>> {
+    match sqlx::query!(r#"SELECT '' ~* $1 AS "matches!""#, regex)
+        .fetch_one(db)
+        .await
+    {
+        Ok(_) => Ok(None),
+        Err(sqlx::Error::Database(e))
+            if e.code().as_deref() == Some(INVALID_REGULAR_EXPRESSION) =>
+        {
+            Ok(Some(e.message().to_owned()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn get_file_jobs_by_selection(
+    db: &Pool<Postgres>,
+    selection: &FileJobSelection,
+) -> Result<Vec<FileJob>> {
+    let file_jobs = sqlx::query_as!(
+        FileJob,
+        r#"SELECT file_jobs.* from file_jobs
+            JOIN files ON files.file_full_path = file_jobs.file_full_path
+            WHERE ($1::TEXT IS NULL OR file_jobs.preset_name = $1)
+              AND (NOT $2 OR (file_jobs.has_succeeded = false AND file_jobs.finished_at IS NOT NULL))
+              AND ($3::TEXT IS NULL OR file_jobs.file_full_path ~ $3)
+              AND ($4::TEXT IS NULL OR files.mime_type ~ $4
+                OR (files.mime_type IS NULL AND lower(files.extension) = ANY($6)))
+              AND ($5::TEXT IS NULL OR file_jobs.command_log ~* $5)
+            ORDER BY file_jobs.preset_name, file_jobs.file_full_path
         "#,
+        selection.preset_name,
+        selection.only_failed,
+        selection.path_regex,
+        selection.mime_type_regex,
+        selection.error_regex,
+        &selection.mime_type_extensions[..]
     )
         .fetch_all(db)
         .await?;
     Ok(file_jobs)
-}
\ No newline at end of file
+}
+
+pub async fn reset_file_jobs(db: &Pool<Postgres>, file_jobs: &[FileJob]) -> Result<()> {
+    let file_full_path_values: Vec<String> =
+        file_jobs.iter().map(|f| f.file_full_path.clone()).collect();
+    let preset_name_values: Vec<String> = file_jobs.iter().map(|f| f.preset_name.clone()).collect();
+
+    sqlx::query!(
+        r#"
+        UPDATE file_jobs SET
+          finished_at = NULL,
+          has_succeeded = NULL,
+          attempts = 0,
+          next_attempt_at = NULL
+        FROM UNNEST($1::TEXT[], $2::TEXT[]) AS t (file_full_path, preset_name)
+        WHERE file_jobs.file_full_path = t.file_full_path AND file_jobs.preset_name = t.preset_name
+        "#,
+        &file_full_path_values[..],
+        &preset_name_values[..]
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Conversion speed of the successful jobs of a preset, unset when there is no history yet.
+#[derive(Debug, Default, Clone)]
+pub struct ConversionThroughput {
+    pub megapixels_per_second: Option<f64>,
+    pub fps: Option<f64>,
+}
+
+pub async fn get_conversion_throughput(
+    db: &Pool<Postgres>,
+    preset_name: &str,
+) -> Result<ConversionThroughput> {
+    let throughput = sqlx::query_as!(
+        ConversionThroughput,
+        r#"SELECT
+            sum(megapixels) / nullif(sum(processing_seconds) FILTER (WHERE megapixels IS NOT NULL), 0) AS megapixels_per_second,
+            avg(fps)::DOUBLE PRECISION AS fps
+            FROM file_jobs
+            WHERE preset_name = $1 AND has_succeeded = true
+        "#,
+        preset_name
+    )
+        .fetch_one(db)
+        .await?;
+    Ok(throughput)
+}
+
+/// Number of file jobs of a preset that are not pending, by reason.
+#[derive(Debug, Default, Clone)]
+pub struct FinishedFileJobCounts {
+    pub succeeded: i64,
+    pub failed: i64,
+    pub waiting_for_retry: i64,
+}
+
+pub async fn count_finished_file_jobs(
+    db: &Pool<Postgres>,
+    preset_name: &str,
+) -> Result<FinishedFileJobCounts> {
+    let counts = sqlx::query_as!(
+        FinishedFileJobCounts,
+        r#"SELECT
+            count(*) FILTER (WHERE finished_at IS NOT NULL AND has_succeeded = true) AS "succeeded!",
+            count(*) FILTER (WHERE finished_at IS NOT NULL AND has_succeeded = false) AS "failed!",
+            count(*) FILTER (WHERE finished_at IS NULL AND next_attempt_at > $2) AS "waiting_for_retry!"
+            FROM file_jobs
+            WHERE preset_name = $1
+        "#,
+        preset_name,
+        now()
+    )
+        .fetch_one(db)
+        .await?;
+    Ok(counts)
+}
+
+/// Number of unfinished file jobs of each preset having any.
+pub async fn count_unfinished_file_jobs(db: &Pool<Postgres>) -> Result<Vec<(String, i64)>> {
+    let counts = sqlx::query!(
+        r#"SELECT preset_name, count(*) AS "count!" FROM file_jobs
+            WHERE finished_at IS NULL
+            GROUP BY preset_name
+        "#
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(counts
+        .into_iter()
+        .map(|row| (row.preset_name, row.count))
+        .collect())
+}
+
+/// A still and a clip of an Apple Live Photo, paired by their content identifier.
+#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+pub struct LivePhoto {
+    pub still_full_path: String,
+    pub clip_full_path: String,
+    pub content_identifier: String,
+    #[serde(skip)]
+    pub created_at: PrimitiveDateTime,
+}
+
+/// A still and a clip in the same folder with the same stem, the way iPhones name the two files
+/// of a Live Photo, with the modification dates they are checked at.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LivePhotoCandidate {
+    pub still_full_path: String,
+    pub clip_full_path: String,
+    pub still_modified_at: PrimitiveDateTime,
+    pub clip_modified_at: PrimitiveDateTime,
+}
+
+/// Candidates not paired yet, leaving out the ones rejected before unless a file changed since.
+pub async fn get_live_photo_candidates(db: &Pool<Postgres>) -> Result<Vec<LivePhotoCandidate>> {
+    let candidates = sqlx::query_as!(
+        LivePhotoCandidate,
+        r#"SELECT stills.file_full_path AS "still_full_path!", clips.file_full_path AS "clip_full_path!",
+                  stills.file_modified_at AS "still_modified_at!", clips.file_modified_at AS "clip_modified_at!"
+            FROM files stills
+            JOIN files clips ON clips.folder_full_path = stills.folder_full_path AND clips.stem = stills.stem
+            WHERE stills.extension IN ('heic', 'heif', 'jpg', 'jpeg')
+              AND clips.extension = 'mov'
+              AND NOT EXISTS (SELECT 1 FROM live_photos WHERE live_photos.still_full_path = stills.file_full_path)
+              AND NOT EXISTS (SELECT 1 FROM live_photos WHERE live_photos.clip_full_path = clips.file_full_path)
+              AND NOT EXISTS (
+                SELECT 1 FROM rejected_live_photo_candidates rejected
+                WHERE rejected.still_full_path = stills.file_full_path
+                  AND rejected.clip_full_path = clips.file_full_path
+                  AND rejected.still_modified_at = stills.file_modified_at
+                  AND rejected.clip_modified_at = clips.file_modified_at
+              )
+            ORDER BY stills.file_full_path
+        "#
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(candidates)
+}
+
+/// Remembers that the content identifiers of the candidates differ, or could not be read.
+pub async fn upsert_rejected_live_photo_candidates(
+    db: &Pool<Postgres>,
+    candidates: &[LivePhotoCandidate],
+) -> Result<()> {
+    let still_full_paths: Vec<String> =
+        candidates.iter().map(|c| c.still_full_path.clone()).collect();
+    let clip_full_paths: Vec<String> =
+        candidates.iter().map(|c| c.clip_full_path.clone()).collect();
+    let still_modified_ats: Vec<PrimitiveDateTime> =
+        candidates.iter().map(|c| c.still_modified_at).collect();
+    let clip_modified_ats: Vec<PrimitiveDateTime> =
+        candidates.iter().map(|c| c.clip_modified_at).collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO rejected_live_photo_candidates
+          (still_full_path, clip_full_path, still_modified_at, clip_modified_at, created_at)
+        SELECT *, $5::TIMESTAMP
+        FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TIMESTAMP[], $4::TIMESTAMP[])
+        ON CONFLICT (still_full_path, clip_full_path) DO UPDATE
+        SET still_modified_at = EXCLUDED.still_modified_at,
+            clip_modified_at = EXCLUDED.clip_modified_at,
+            created_at = EXCLUDED.created_at
+        "#,
+        &still_full_paths[..],
+        &clip_full_paths[..],
+        &still_modified_ats[..],
+        &clip_modified_ats[..],
+        now()
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Stores the pairing, and drops the unfinished jobs of the clip for presets other than
+/// `kept_preset_names`, since it is no longer converted as a standalone video.
+pub async fn insert_live_photo(
+    db: &Pool<Postgres>,
+    live_photo: &LivePhoto,
+    kept_preset_names: &[&str],
+) -> Result<()> {
+    let mut transaction = db.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO live_photos (still_full_path, clip_full_path, content_identifier, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+        "#,
+        live_photo.still_full_path,
+        live_photo.clip_full_path,
+        live_photo.content_identifier,
+        live_photo.created_at
+    )
+        .execute(&mut transaction)
+        .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM file_jobs
+        WHERE file_full_path = $1 AND preset_name <> ALL($2) AND finished_at IS NULL
+        "#,
+        live_photo.clip_full_path,
+        &kept_preset_names
+            .iter()
+            .map(|preset_name| preset_name.to_string())
+            .collect::<Vec<String>>()[..]
+    )
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+pub async fn get_live_photos(
+    db: &Pool<Postgres>,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<LivePhoto>> {
+    let live_photos = sqlx::query_as!(
+        LivePhoto,
+        r#"
+        SELECT * FROM live_photos
+        ORDER BY still_full_path
+        OFFSET $1 ROWS
+        FETCH NEXT $2 ROWS ONLY
+        "#,
+        offset,
+        limit
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(live_photos)
+}
+
+/// A photo with a video appended, extracted to its own file for the video presets.
+#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+pub struct MotionPhoto {
+    pub photo_full_path: String,
+    pub video_full_path: String,
+    #[serde(skip)]
+    pub created_at: PrimitiveDateTime,
+}
+
+pub async fn insert_motion_photo(db: &Pool<Postgres>, motion_photo: &MotionPhoto) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO motion_photos (photo_full_path, video_full_path, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        motion_photo.photo_full_path,
+        motion_photo.video_full_path,
+        motion_photo.created_at
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_motion_photos(
+    db: &Pool<Postgres>,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<MotionPhoto>> {
+    let motion_photos = sqlx::query_as!(
+        MotionPhoto,
+        r#"
+        SELECT * FROM motion_photos
+        ORDER BY photo_full_path
+        OFFSET $1 ROWS
+        FETCH NEXT $2 ROWS ONLY
+        "#,
+        offset,
+        limit
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(motion_photos)
+}
+
+/// Blurred preview of a file, shown by galleries while its thumbnail loads.
+#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+pub struct FilePlaceholder {
+    pub file_full_path: String,
+    pub blurhash: String,
+    /// Dimensions of the thumbnail the BlurHash was computed from, for its aspect ratio.
+    pub width: i32,
+    pub height: i32,
+    #[serde(skip)]
+    pub created_at: PrimitiveDateTime,
+}
+
+/// Replaces the placeholder of the file, as it is computed again when the file is converted again.
+pub async fn upsert_placeholder(db: &Pool<Postgres>, placeholder: &FilePlaceholder) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO placeholders (file_full_path, blurhash, width, height, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (file_full_path) DO UPDATE
+        SET blurhash = EXCLUDED.blurhash,
+            width = EXCLUDED.width,
+            height = EXCLUDED.height,
+            created_at = EXCLUDED.created_at
+        "#,
+        placeholder.file_full_path,
+        placeholder.blurhash,
+        placeholder.width,
+        placeholder.height,
+        placeholder.created_at
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_placeholders(
+    db: &Pool<Postgres>,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<FilePlaceholder>> {
+    let placeholders = sqlx::query_as!(
+        FilePlaceholder,
+        r#"
+        SELECT * FROM placeholders
+        ORDER BY file_full_path
+        OFFSET $1 ROWS
+        FETCH NEXT $2 ROWS ONLY
+        "#,
+        offset,
+        limit
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(placeholders)
+}
+
+/// Difference hash of an image file, close to the one of its resized or recompressed copies.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FilePerceptualHash {
+    pub file_full_path: String,
+    /// The 64 bits of the hash, stored as a signed integer.
+    pub dhash: i64,
+    pub created_at: PrimitiveDateTime,
+}
+
+/// Replaces the hash of the file, as it is computed again when the file is converted again.
+pub async fn upsert_perceptual_hash(db: &Pool<Postgres>, hash: &FilePerceptualHash) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO perceptual_hashes (file_full_path, dhash, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (file_full_path) DO UPDATE
+        SET dhash = EXCLUDED.dhash,
+            created_at = EXCLUDED.created_at
+        "#,
+        hash.file_full_path,
+        hash.dhash,
+        hash.created_at
+    )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_perceptual_hashes(db: &Pool<Postgres>) -> Result<Vec<FilePerceptualHash>> {
+    let hashes = sqlx::query_as!(
+        FilePerceptualHash,
+        r#"SELECT * FROM perceptual_hashes ORDER BY file_full_path"#
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(hashes)
+}
+
+/// A file whose image is close to the one of another file.
+#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+pub struct SimilarFile {
+    pub file_full_path: String,
+    /// Bits differing between the hashes of the two files.
+    pub distance: i64,
+}
+
+/// Files whose hash differs by at most `max_distance` bits from the one of the given file,
+/// closest first.
+pub async fn get_similar_files(
+    db: &Pool<Postgres>,
+    file_full_path: &str,
+    max_distance: i64,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<SimilarFile>> {
+    let similar_files = sqlx::query_as!(
+        SimilarFile,
+        r#"
+        SELECT other.file_full_path, bit_count((other.dhash # file.dhash)::bit(64)) AS "distance!"
+        FROM perceptual_hashes file
+        JOIN perceptual_hashes other ON other.file_full_path <> file.file_full_path
+        WHERE file.file_full_path = $1
+        AND bit_count((other.dhash # file.dhash)::bit(64)) <= $2
+        ORDER BY 2, 1
+        OFFSET $3 ROWS
+        FETCH NEXT $4 ROWS ONLY
+        "#,
+        file_full_path,
+        max_distance,
+        offset,
+        limit
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(similar_files)
+}
+
+/// A file holding metadata about a media file, written into its outputs.
+#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+pub struct Sidecar {
+    pub sidecar_full_path: String,
+    pub file_full_path: String,
+    #[serde(skip)]
+    pub created_at: PrimitiveDateTime,
+}
+
+/// Associates sidecar files, by extension, with the media files of their folder named after them:
+/// `IMG_1234.xmp` or `IMG_1234.jpg.xmp` with `IMG_1234.jpg`, and Google Takeout variants such as
+/// `IMG_1234.jpg.supplemental-metadata.json`. Returns the number of new associations.
+pub async fn associate_sidecars(db: &Pool<Postgres>, sidecar_extensions: &[&str]) -> Result<u64> {
+    let sidecar_extensions: Vec<String> = sidecar_extensions
+        .iter()
+        .map(|extension| extension.to_string())
+        .collect();
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO sidecars (sidecar_full_path, file_full_path, created_at)
+        SELECT sidecar.file_full_path, media.file_full_path, $2
+        FROM files sidecar
+        JOIN files media ON media.folder_full_path = sidecar.folder_full_path
+        WHERE lower(sidecar.extension) = ANY($1)
+          AND lower(media.extension) <> ALL($1)
+          AND (sidecar.stem = media.stem
+            OR sidecar.stem = media.name
+            OR starts_with(sidecar.stem, media.name || '.'))
+        ON CONFLICT DO NOTHING
+        "#,
+        &sidecar_extensions[..],
+        now()
+    )
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn get_sidecars(
+    db: &Pool<Postgres>,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<Sidecar>> {
+    let sidecars = sqlx::query_as!(
+        Sidecar,
+        r#"
+        SELECT * FROM sidecars
+        ORDER BY file_full_path, sidecar_full_path
+        OFFSET $1 ROWS
+        FETCH NEXT $2 ROWS ONLY
+        "#,
+        offset,
+        limit
+    )
+        .fetch_all(db)
+        .await?;
+    Ok(sidecars)
+}
+
+/// Sidecar files of each of the given files having any.
+pub async fn get_sidecars_of_files(
+    db: &Pool<Postgres>,
+    files: &[File],
+) -> Result<HashMap<String, Vec<String>>> {
+    let file_full_paths: Vec<String> = files.iter().map(|f| f.file_full_path.clone()).collect();
+    let rows = sqlx::query!(
+        r#"SELECT file_full_path, sidecar_full_path FROM sidecars
+            WHERE file_full_path = ANY($1)
+            ORDER BY sidecar_full_path
+        "#,
+        &file_full_paths[..]
+    )
+        .fetch_all(db)
+        .await?;
+    let mut sidecars: HashMap<String, Vec<String>> = HashMap::new();
+    for row in rows {
+        sidecars
+            .entry(row.file_full_path)
+            .or_default()
+            .push(row.sidecar_full_path);
+    }
+    Ok(sidecars)
+}
@@ -0,0 +1,260 @@
+use crate::db::{self, FilePerceptualHash};
+use crate::perceptual_hash::hamming_distance;
+use crate::AppContext;
+use anyhow::Result;
+use csv::Writer;
+
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct DuplicatesArgs {
+    /// Largest number of differing hash bits between near-duplicates, instead of the
+    /// NEAR_DUPLICATE_MAX_DISTANCE setting.
+    #[arg(long)]
+    pub max_distance: Option<u32>,
+}
+
+/// Near-duplicate files, each with its distance to the first one.
+#[derive(Debug)]
+struct DuplicateGroup {
+    files: Vec<(String, u32)>,
+}
+
+/// Groups each file with the files at most `max_distance` bits away from it that are not in a
+/// previous group yet, so files of a group are at most twice that distance apart instead of
+/// chaining through other files. Files without near-duplicates are left out.
+fn group_near_duplicates(hashes: &[FilePerceptualHash], max_distance: u32) -> Vec<DuplicateGroup> {
+    let mut tree = BkTree::default();
+    for (index, hash) in hashes.iter().enumerate() {
+        tree.insert(hash.dhash as u64, index);
+    }
+    let mut is_grouped = vec![false; hashes.len()];
+    let mut groups = vec![];
+    for (centre, centre_hash) in hashes.iter().enumerate() {
+        if is_grouped[centre] {
+            continue;
+        }
+        let centre_hash = centre_hash.dhash as u64;
+        let mut members: Vec<(u32, usize)> = tree
+            .find(centre_hash, max_distance)
+            .into_iter()
+            .filter(|&index| !is_grouped[index])
+            .map(|index| {
+                (
+                    hamming_distance(centre_hash, hashes[index].dhash as u64),
+                    index,
+                )
+            })
+            .collect();
+        if members.len() < 2 {
+            continue;
+        }
+        // The centre comes first, at distance 0, then the closest files.
+        members.sort_by_key(|&(distance, index)| (index != centre, distance, index));
+        for &(_, index) in members.iter() {
+            is_grouped[index] = true;
+        }
+        groups.push(DuplicateGroup {
+            files: members
+                .into_iter()
+                .map(|(distance, index)| (hashes[index].file_full_path.clone(), distance))
+                .collect(),
+        });
+    }
+    groups
+}
+
+/// Burkhard-Keller tree of the hashes, which skips the subtrees too far from the searched hash
+/// instead of comparing it to all of them.
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    index: usize,
+    /// Nodes whose hash is the given distance away from this one.
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, index: usize) {
+        let new_node = self.nodes.len();
+        self.nodes.push(BkNode {
+            hash,
+            index,
+            children: vec![],
+        });
+        if new_node == 0 {
+            return;
+        }
+        let mut node = 0;
+        loop {
+            let distance = hamming_distance(self.nodes[node].hash, hash);
+            match self.nodes[node]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+            {
+                Some(&(_, child)) => node = child,
+                None => {
+                    self.nodes[node].children.push((distance, new_node));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Indexes of the hashes at most `max_distance` bits away from `hash`.
+    fn find(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+        let mut found = vec![];
+        let mut pending = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(node) = pending.pop() {
+            let node = &self.nodes[node];
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                found.push(node.index);
+            }
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= max_distance)
+                    .map(|&(_, child)| child),
+            );
+        }
+        found
+    }
+}
+
+/// Prints the groups of near-duplicate photos and writes them to the near duplicates report.
+pub async fn run(ctx: &AppContext, args: &DuplicatesArgs) -> Result<()> {
+    let max_distance = args
+        .max_distance
+        .unwrap_or(ctx.config.near_duplicate_max_distance);
+    let hashes = db::get_perceptual_hashes(&ctx.db).await?;
+    let groups = group_near_duplicates(&hashes, max_distance);
+
+    let report_path = format!("{}/near_duplicates.csv", ctx.config.logs_folder);
+    let mut writer = Writer::from_path(&report_path)?;
+    writer.write_record(["group", "file_full_path", "distance"])?;
+    for (number, group) in groups.iter().enumerate() {
+        println!("Group {}:", number + 1);
+        for (file_full_path, distance) in group.files.iter() {
+            println!("  {distance}\t{file_full_path}");
+            writer.write_record([
+                (number + 1).to_string(),
+                file_full_path.clone(),
+                distance.to_string(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    println!(
+        "{} groups of near-duplicates among {} photos, at most {max_distance} bits apart. Report written to {report_path}.",
+        groups.len(),
+        hashes.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::now;
+
+    fn hash(file_full_path: &str, dhash: u64) -> FilePerceptualHash {
+        FilePerceptualHash {
+            file_full_path: file_full_path.to_owned(),
+            dhash: dhash as i64,
+            created_at: now(),
+        }
+    }
+
+    fn paths(group: &DuplicateGroup) -> Vec<(&str, u32)> {
+        group
+            .files
+            .iter()
+            .map(|(path, distance)| (path.as_str(), *distance))
+            .collect()
+    }
+
+    #[test]
+    fn bk_tree_finds_the_same_hashes_as_comparing_all_of_them() {
+        // Deterministic pseudo-random hashes, some of them close to each other.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut hashes = vec![];
+        for _ in 0..200 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            hashes.push(state);
+            hashes.push(state ^ (1 << (state % 64)) ^ (1 << (state % 7)));
+        }
+        let mut tree = BkTree::default();
+        for (index, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, index);
+        }
+
+        for max_distance in [0, 1, 2, 8, 32] {
+            for hash in hashes.iter().step_by(7) {
+                let mut found = tree.find(*hash, max_distance);
+                found.sort_unstable();
+                let expected: Vec<usize> = (0..hashes.len())
+                    .filter(|&index| hamming_distance(hashes[index], *hash) <= max_distance)
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_bk_tree_finds_nothing() {
+        assert!(BkTree::default().find(0, 64).is_empty());
+    }
+
+    #[test]
+    fn groups_do_not_chain_through_their_files() {
+        // b is 3 bits from a and c, which are 6 bits apart.
+        let hashes = [
+            hash("a", 0),
+            hash("b", 0b111),
+            hash("c", 0b111_111),
+            hash("d", u64::MAX),
+        ];
+
+        let groups = group_near_duplicates(&hashes, 4);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(paths(&groups[0]), [("a", 0), ("b", 3)]);
+    }
+
+    #[test]
+    fn groups_start_with_their_first_file_then_the_closest_ones() {
+        let hashes = [
+            hash("a", 0b1),
+            hash("b", 0b1111),
+            hash("c", 0b11),
+            hash("d", u64::MAX),
+            hash("e", u64::MAX << 1),
+        ];
+
+        let groups = group_near_duplicates(&hashes, 3);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(paths(&groups[0]), [("a", 0), ("c", 1), ("b", 3)]);
+        assert_eq!(paths(&groups[1]), [("d", 0), ("e", 1)]);
+    }
+
+    #[test]
+    fn identical_hashes_are_grouped_at_distance_zero() {
+        let hashes = [hash("a", 42), hash("b", 42), hash("c", 43)];
+
+        let groups = group_near_duplicates(&hashes, 0);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(paths(&groups[0]), [("a", 0), ("b", 0)]);
+    }
+}
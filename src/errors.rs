@@ -13,6 +13,9 @@ pub enum FixMyLibErrors {
 
     #[error("Failure parsing path: {0}")]
     PathParsing(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
 }
 
 impl From<rusqlite::Error> for FixMyLibErrors {
@@ -1,18 +1,203 @@
+use sqlx::types::time::{Date, PrimitiveDateTime, Time};
 use std::path::Path;
 
+/// Tags read into [`Exiftool`]. The ones suffixed with `#` are read as exiftool stores them rather
+/// than as their description, e.g. a duration in seconds rather than `0:01:23`, an orientation
+/// from 1 to 8 rather than `Rotate 90 CW` and a brand like `heic` rather than its name.
+const TAGS: [&str; 19] = [
+    "FileType",
+    "MIMEType",
+    "ImageWidth#",
+    "ImageHeight#",
+    "Duration#",
+    "VideoFrameRate#",
+    "ProfileDescription",
+    "TransferCharacteristics#",
+    "Orientation#",
+    "DateTimeOriginal",
+    "CreateDate",
+    "ContentIdentifier",
+    "MotionPhoto#",
+    "MicroVideo#",
+    "MicroVideoOffset#",
+    "EmbeddedVideoType",
+    "FrameCount#",
+    "AnimationLoopCount#",
+    "MajorBrand#",
+];
+
 #[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Exiftool {
     pub file_type: String,
     #[serde(rename = "MIMEType")]
     pub mime_type: String,
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub image_width: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub image_height: Option<f64>,
+    /// Media duration in seconds.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub duration: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub video_frame_rate: Option<f64>,
+    /// Description of the embedded ICC profile, e.g. `Display P3`.
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub profile_description: Option<String>,
+    /// Transfer function of HEIF and AVIF images, 16 for PQ and 18 for HLG in HDR ones.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub transfer_characteristics: Option<f64>,
+    /// EXIF orientation, from 1 for normal to 8.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub orientation: Option<f64>,
+    /// When the photo was taken, e.g. `2023:05:01 12:30:00`, with optional subseconds and offset.
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub date_time_original: Option<String>,
+    /// When the file was created by the camera, the capture date of most videos.
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub create_date: Option<String>,
+    /// Shared by the still and the clip of an Apple Live Photo.
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub content_identifier: Option<String>,
+    /// Set to 1 by Google cameras on motion photos, `MicroVideo` on the older ones.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub motion_photo: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub micro_video: Option<f64>,
+    /// Distance in bytes from the end of the file to the embedded video of older motion photos.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub micro_video_offset: Option<f64>,
+    /// `MotionPhoto_Data` in the trailer of Samsung motion photos.
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub embedded_video_type: Option<String>,
+    /// Number of frames of GIF and WebP animations.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub frame_count: Option<f64>,
+    /// Only written in the animation chunk of animated WebP files.
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub animation_loop_count: Option<f64>,
+    /// Brand of ISO media files, `msf1` or `hevc` for HEIF image sequences and `avis` for AVIF ones.
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub major_brand: Option<String>,
+}
+
+impl Exiftool {
+    pub fn megapixels(&self) -> Option<f64> {
+        match (self.image_width, self.image_height) {
+            (Some(width), Some(height)) => Some(width * height / 1_000_000.0),
+            _ => None,
+        }
+    }
+
+    /// When the media was captured, in the local time of the camera.
+    pub fn capture_date(&self) -> Option<PrimitiveDateTime> {
+        [&self.date_time_original, &self.create_date]
+            .into_iter()
+            .flatten()
+            .find_map(|date| parse_exif_date(date))
+    }
+
+    /// Whether the photo has a video appended, as Google and Samsung cameras do.
+    pub fn is_motion_photo(&self) -> bool {
+        self.motion_photo == Some(1.0)
+            || self.micro_video == Some(1.0)
+            || self
+                .embedded_video_type
+                .as_deref()
+                .is_some_and(|video_type| video_type.starts_with("MotionPhoto"))
+    }
+
+    pub fn has_color_profile(&self) -> bool {
+        self.profile_description.is_some()
+    }
+
+    /// Images without a profile are assumed to be sRGB.
+    pub fn is_srgb(&self) -> bool {
+        match &self.profile_description {
+            Some(description) => description.contains("sRGB"),
+            None => true,
+        }
+    }
+
+    /// Whether the image has an HDR transfer function. Photos with a gain map have an SDR primary
+    /// image, so they are not.
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.transfer_characteristics, Some(transfer) if transfer == 16.0 || transfer == 18.0)
+    }
+
+    /// Whether the image is an animation, as opposed to a still, possibly with several pages.
+    pub fn is_animated(&self) -> bool {
+        self.frame_count
+            .is_some_and(|frame_count| frame_count > 1.0)
+            || self.animation_loop_count.is_some()
+            || self.mime_type.ends_with("-sequence")
+            || self
+                .major_brand
+                .as_deref()
+                .is_some_and(|brand| matches!(brand.trim(), "msf1" | "hevc" | "avis"))
+    }
+}
+
+/// Parses the `YYYY:MM:DD HH:MM:SS` dates of EXIF and QuickTime tags, ignoring subseconds and
+/// offsets. Unset dates are written as zeros by some cameras.
+fn parse_exif_date(date: &str) -> Option<PrimitiveDateTime> {
+    let mut fields = date
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|field| !field.is_empty());
+    let mut next = || fields.next()?.parse::<u32>().ok();
+    let (year, month, day) = (next()?, next()?, next()?);
+    let (hour, minute, second) = (next()?, next()?, next()?);
+    let date = Date::from_calendar_date(
+        i32::try_from(year).ok()?,
+        u8::try_from(month).ok()?.try_into().ok()?,
+        u8::try_from(day).ok()?,
+    )
+    .ok()?;
+    let time = Time::from_hms(
+        u8::try_from(hour).ok()?,
+        u8::try_from(minute).ok()?,
+        u8::try_from(second).ok()?,
+    )
+    .ok()?;
+    Some(PrimitiveDateTime::new(date, time))
+}
+
+/// Tags have no fixed type across file formats, so anything that is not a number is ignored.
+fn lenient_number<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: serde_json::Value = serde::Deserialize::deserialize(deserializer)?;
+    let number = match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    Ok(number.filter(|n: &f64| n.is_finite()))
+}
+
+fn lenient_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: serde_json::Value = serde::Deserialize::deserialize(deserializer)?;
+    let string = match value {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+    Ok(string.filter(|s| !s.trim().is_empty()))
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum ExiftoolError {
+    #[error("Failure running exiftool: {0}")]
     Io(std::io::Error),
+    #[error("exiftool exited with {}: {}", .0.status, String::from_utf8_lossy(&.0.stderr).trim())]
     Status(std::process::Output),
+    #[error("Failure parsing the exiftool output: {0}")]
     Deserialize(serde_json::Error),
+    #[error("exiftool found no metadata")]
     MissingElement,
 }
 
@@ -31,16 +216,14 @@ pub fn exiftool_on_dir(path: impl AsRef<Path>) -> Result<Vec<Exiftool>, Exiftool
 
     let mut cmd = std::process::Command::new("exiftool");
 
-    cmd.args(["-j"]);
+    cmd.arg("-j");
+    cmd.args(TAGS.iter().map(|tag| format!("-{tag}")));
     cmd.arg(path);
 
     let out = cmd.output().map_err(ExiftoolError::Io)?;
     if !out.status.success() {
         return Err(ExiftoolError::Status(out));
     }
-    let _exiftool_string = String::from_utf8(out.stdout.clone()).unwrap();
-    let exiftool_list: Vec<Exiftool> = serde_json::from_slice(&out.stdout)
-        .map_err(ExiftoolError::Deserialize)
-        .unwrap();
-    Ok(exiftool_list)
+    // Output missing a field, like the MIME type, fails this file instead of stopping the caller.
+    serde_json::from_slice(&out.stdout).map_err(ExiftoolError::Deserialize)
 }
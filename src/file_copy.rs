@@ -0,0 +1,88 @@
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// How a file ended up at its destination.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyMethod {
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+impl Display for CopyMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyMethod::Reflink => write!(f, "reflink"),
+            CopyMethod::Hardlink => write!(f, "hardlink"),
+            CopyMethod::Copy => write!(f, "copy"),
+        }
+    }
+}
+
+/// Places the content of `from` at `to` without duplicating data when possible: a reflink on
+/// filesystems supporting them, else a hardlink when both are on the same filesystem, else a
+/// copy verified by checksum.
+pub fn link_or_copy(from: &Path, to: &Path) -> io::Result<CopyMethod> {
+    remove_if_exists(to)?;
+    if reflink(from, to).is_ok() {
+        return Ok(CopyMethod::Reflink);
+    }
+    if std::fs::hard_link(from, to).is_ok() {
+        return Ok(CopyMethod::Hardlink);
+    }
+    copy_verified(from, to)?;
+    Ok(CopyMethod::Copy)
+}
+
+fn copy_verified(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::copy(from, to)?;
+    File::open(to)?.sync_all()?;
+    let (source_checksum, destination_checksum) = (checksum(from)?, checksum(to)?);
+    if source_checksum != destination_checksum {
+        remove_if_exists(to)?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checksum of the copy {} differs from the checksum of the source {}",
+                hex(&destination_checksum),
+                hex(&source_checksum)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn checksum(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    let source = File::open(from)?;
+    let destination = File::create(to)?;
+    // SAFETY: both descriptors are open for the duration of the call.
+    let result = unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if result == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    drop(destination);
+    remove_if_exists(to)?;
+    Err(error)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -1,79 +1,498 @@
-use crate::processor::{CommandRunner, FileToBeProcessed, ProcessingResult};
+use crate::color::{ColorSpace, DEFAULT_COLOR_SPACE};
+use crate::command_runner::{CommandLine, CommandRunner};
+use crate::config::parse_preset_values;
+use crate::converter::{Converter, ExtraOutput, Throughput};
+use crate::crop::{Crop, Crops};
+use crate::image_encoding::{JpegEncoding, JpegEncodings};
+use crate::live_photo::LIVE_PRESET_NAME;
+use crate::motion_photo::{motion_photo_video_path, motion_photo_video_temporary_path};
+use crate::native_image::{ImageOutput, NATIVE_MIME_TYPES};
+use crate::original_converter::ORIGINAL_PRESET_NAME;
+use crate::perceptual_hash::with_perceptual_hash;
+use crate::placeholder::with_placeholder;
+use crate::processor::{
+    get_preset_names, FileToBeProcessed, ImageMetrics, ProcessingMetrics, ProcessingResult,
+};
+use crate::sidecar::{has_sidecar_metadata, sidecar_commands};
 use crate::AppContext;
-use rayon::iter::ParallelIterator;
-use rayon::prelude::IntoParallelIterator;
-use rayon::ThreadPool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-pub struct ImageConverterProcessor {
-    pool: ThreadPool,
+/// Typical size of a JPEG photo at the default ImageMagick quality.
+const JPEG_BYTES_PER_PIXEL: f64 = 0.4;
+/// Extensions of camera RAW files, whose MIME types vary between cameras and exiftool versions.
+pub const RAW_EXTENSIONS: [&str; 16] = [
+    "dng", "cr2", "cr3", "crw", "nef", "nrw", "arw", "srf", "sr2", "orf", "rw2", "raf", "pef",
+    "srw", "3fr", "iiq",
+];
+
+/// Tags copied from the source into the image outputs.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ImageMetadata {
+    All,
+    Dates,
+    None,
+}
+
+pub struct ImageConverter {
+    metadata: ImageMetadata,
+    color_spaces: HashMap<String, ColorSpace>,
+    crops: Crops,
+    encodings: JpegEncodings,
+    /// ICC profiles written to files for ImageMagick and exiftool.
+    profile_paths: HashMap<ColorSpace, PathBuf>,
+    /// First enabled preset converting images, the only one extracting the videos of motion
+    /// photos.
+    motion_photo_preset: Option<String>,
+    /// First enabled preset converting images without cropping them, the only one hashing its
+    /// outputs. Cropped outputs would not match the other copies of the photo.
+    perceptual_hash_preset: Option<String>,
 }
 
-impl ImageConverterProcessor {
-    pub fn new(ctx: &AppContext) -> ImageConverterProcessor {
-        ImageConverterProcessor {
-            pool: rayon::ThreadPoolBuilder::new()
-                .num_threads(ctx.config.image_converter_threads)
-                .build()
-                .unwrap_or_else(|e| panic!("Failure initing threadpool for image processing: {e}")),
+impl ImageConverter {
+    pub fn new(ctx: &AppContext) -> ImageConverter {
+        let profile_paths = [ColorSpace::Srgb, ColorSpace::DisplayP3]
+            .into_iter()
+            .map(|color_space| {
+                let path = color_space.icc_profile_path().unwrap_or_else(|e| {
+                    panic!("Failure writing the {color_space:?} ICC profile: {e}")
+                });
+                (color_space, path)
+            })
+            .collect();
+        let crops = Crops::new(&ctx.config);
+        let image_presets: Vec<String> = get_preset_names(ctx)
+            .into_iter()
+            .filter(|preset_name| is_image_preset(preset_name))
+            .collect();
+        let perceptual_hash_preset = image_presets
+            .iter()
+            .find(|preset_name| crops.preset(preset_name).is_none())
+            .cloned();
+        ImageConverter {
+            metadata: ctx.config.image_metadata,
+            color_spaces: parse_preset_values("IMAGE_COLOR_SPACES", &ctx.config.image_color_spaces),
+            crops,
+            encodings: JpegEncodings::new(&ctx.config),
+            profile_paths,
+            motion_photo_preset: image_presets.into_iter().next(),
+            perceptual_hash_preset,
         }
     }
 
-    pub fn convert_files(&self, files: Vec<FileToBeProcessed>) -> Vec<ProcessingResult> {
-        let mut r: Vec<ProcessingResult> = vec![];
+    fn crop(&self, preset_name: &str) -> Option<Crop> {
+        self.crops.preset(preset_name)
+    }
 
-        self.pool.install(|| {
-            r = files
-                .into_par_iter()
-                .map(|file| ImageConverter::from(&file).run())
-                .collect();
-        });
-        r
+    /// Geometry and encoding of the outputs of the preset, for the in process steps.
+    fn image_output(&self, preset_name: &str) -> ImageOutput {
+        ImageOutput {
+            size: resize_size(preset_name),
+            crop: self.crop(preset_name),
+            color_space: self.color_space(preset_name),
+            encoding: self.encoding(preset_name),
+        }
+    }
+
+    /// Adds to `runner` the resize of `input` by ImageMagick, see [`resize`]. Cropped outputs are
+    /// written as PNGs instead, then cropped and encoded in process.
+    fn resize_with_imagemagick(
+        &self,
+        file: &FileToBeProcessed,
+        runner: CommandRunner,
+        input: impl AsRef<Path>,
+        orientation: Option<u8>,
+        profiles: &[&Path],
+        output: &Path,
+    ) -> CommandRunner {
+        let size = resize_size(file.preset_name);
+        match self.crop(file.preset_name) {
+            Some(crop) => runner
+                .with(resize(
+                    input,
+                    crop.dimensions(size),
+                    orientation,
+                    profiles,
+                    &[],
+                    format!("png:{}", output.display()),
+                ))
+                .encode_natively(output, self.image_output(file.preset_name)),
+            None => runner.with(resize(
+                input,
+                (size, size),
+                orientation,
+                profiles,
+                &self.encoding_args(file.preset_name),
+                output,
+            )),
+        }
+    }
+
+    fn encoding(&self, preset_name: &str) -> JpegEncoding {
+        self.encodings.preset(preset_name)
+    }
+
+    /// ImageMagick options writing the output of the preset. sRGB is the default of viewers, so
+    /// stripped outputs can leave out its profile.
+    fn encoding_args(&self, preset_name: &str) -> Vec<String> {
+        let keep_profile = self.color_space(preset_name) != ColorSpace::Srgb;
+        self.encoding(preset_name).imagemagick_args(keep_profile)
+    }
+
+    fn color_space(&self, preset_name: &str) -> ColorSpace {
+        self.color_spaces
+            .get(preset_name)
+            .copied()
+            .unwrap_or(DEFAULT_COLOR_SPACE)
+    }
+
+    fn profile_path(&self, color_space: ColorSpace) -> &Path {
+        &self.profile_paths[&color_space]
+    }
+
+    /// ImageMagick converts from the profile embedded in the input to the first one given, or
+    /// assigns it when there is none. Inputs without a profile are assumed to be sRGB.
+    fn profiles(&self, file: &FileToBeProcessed, has_profile: bool) -> Vec<&Path> {
+        let target = self.profile_path(self.color_space(file.preset_name));
+        if has_profile {
+            vec![target]
+        } else {
+            vec![self.profile_path(ColorSpace::Srgb), target]
+        }
+    }
+
+    /// A JPEG whose smallest side already fits the preset is used as is, unless its pixels need
+    /// to be rotated, its colors converted, some of its metadata removed or replaced by the one
+    /// of its sidecars, or it has to be encoded differently.
+    fn is_compatible(&self, file: &FileToBeProcessed) -> bool {
+        let size = resize_size(file.preset_name) as f64;
+        let file_size = u64::try_from(file.file.size).unwrap_or(u64::MAX);
+        file.exif.mime_type == "image/jpeg"
+            && self.crop(file.preset_name).is_none()
+            && self.encoding(file.preset_name).accepts_source(file_size)
+            && self.metadata == ImageMetadata::All
+            && !has_sidecar_metadata(file)
+            && orientation(file) == 1
+            && self.color_space(file.preset_name) == ColorSpace::Srgb
+            && file.exif.is_srgb()
+            && matches!(
+                (file.exif.image_width, file.exif.image_height),
+                (Some(width), Some(height)) if width.min(height) <= size
+            )
     }
-}
 
-pub struct ImageConverter<'a> {
-    file: &'a FileToBeProcessed<'a>,
+    /// Copies the selected tags of the source and its sidecars to the output, except the embedded
+    /// previews. The pixels are already rotated, so the orientation is reset to normal.
+    fn copy_metadata(&self, file: &FileToBeProcessed, output: &Path) -> Vec<CommandLine> {
+        if self.encoding(file.preset_name).strip_metadata {
+            return vec![];
+        }
+        // The output has its own color profile, and no HDR gain map.
+        let tags: &[&str] = match self.metadata {
+            ImageMetadata::All => &[
+                "-all:all",
+                "--PreviewImage",
+                "--JpgFromRaw",
+                "--ThumbnailImage",
+                "--Orientation",
+                "--ICC_Profile:all",
+                "--XMP-hdrgm:all",
+            ],
+            ImageMetadata::Dates => &["-AllDates", "-SubSecTime*", "-OffsetTime*"],
+            ImageMetadata::None => return sidecar_commands(file, output),
+        };
+        let mut commands = vec![CommandLine::new("exiftool")
+            .args(["-overwrite_original", "-TagsFromFile"])
+            .arg(file.file_full_path())
+            .args(tags)
+            .arg("-Orientation#=1")
+            .arg(output)];
+        commands.extend(sidecar_commands(file, output));
+        commands
+    }
 }
 
-impl ImageConverter<'_> {
-    pub fn from<'a>(file: &'a FileToBeProcessed) -> ImageConverter<'a> {
-        ImageConverter { file }
+impl Converter for ImageConverter {
+    fn name(&self) -> &'static str {
+        "image"
     }
 
-    fn run(&self) -> ProcessingResult {
-        CommandRunner::build(self.file.output_folder)
-            .with(self.define_input_and_output_paths())
-            .with(match self.file.preset_name {
-                "thumbnail" => self.convert_image_thumbnail(),
-                _ => self.convert_image_preview(),
-            })
-            .with(self.copy_file_modification_date())
-            .run()
+    fn handles(&self, file: &FileToBeProcessed) -> bool {
+        file.is_image() && !file.exif.is_animated()
+    }
+
+    fn output_extension(&self, _file: &FileToBeProcessed) -> String {
+        "jpg".to_owned()
+    }
+
+    fn commands(&self, file: &FileToBeProcessed, output: &Path) -> Vec<(&'static str, CommandRunner)> {
+        let runner = || {
+            CommandRunner::build(file.output_folder, file.shutdown)
+                .with_timeout(file.timeout)
+                .create_folder(file.output_folder_path())
+        };
+        let mut commands = vec![];
+        if self.is_compatible(file) {
+            commands.push((
+                "passthrough",
+                runner()
+                    .link_or_copy(file.file_full_path(), output)
+                    .copy_file_modification_date(file.file_full_path(), output)
+                    .pass_through(),
+            ));
+        }
+        if is_raw(file) {
+            let size = resize_size(file.preset_name);
+            // Cameras embed a full size JPEG rendering, much faster to resize than the RAW data.
+            // It is stored unrotated, so it is rotated according to the RAW file.
+            commands.push((
+                "embedded-preview",
+                self.resize_with_imagemagick(
+                    file,
+                    runner().extract_embedded_preview(file.file_full_path(), output, size),
+                    output,
+                    Some(orientation(file)),
+                    &self.profiles(file, false),
+                    output,
+                )
+                .with_all(self.copy_metadata(file, output))
+                .copy_file_modification_date(file.file_full_path(), output),
+            ));
+            // dcraw already applies the orientation to the pixels.
+            commands.push((
+                "dcraw",
+                self.resize_with_imagemagick(
+                    file,
+                    runner().with(decode_raw(file).stdout_to(output)),
+                    format!("tiff:{}", output.display()),
+                    None,
+                    &self.profiles(file, false),
+                    output,
+                )
+                .with_all(self.copy_metadata(file, output))
+                .copy_file_modification_date(file.file_full_path(), output),
+            ));
+        }
+        let color_space = self.color_space(file.preset_name);
+        // HDR images are tone mapped by ffmpeg, ImageMagick only clips their highlights. The
+        // tone mapped pixels are already in the color space, so its profile is assigned.
+        if file.exif.is_hdr() {
+            commands.push((
+                "tonemap",
+                self.resize_with_imagemagick(
+                    file,
+                    runner().with(tone_map(file, color_space, output)),
+                    format!("png:{}", output.display()),
+                    None,
+                    &[self.profile_path(color_space)],
+                    output,
+                )
+                .with_all(self.copy_metadata(file, output))
+                .copy_file_modification_date(file.file_full_path(), output),
+            ));
+        }
+        // Common formats are decoded in process, saving the start of ImageMagick on every file.
+        if is_native(file) && !file.exif.is_hdr() {
+            commands.push((
+                "native",
+                runner()
+                    .resize_natively(
+                        file.file_full_path(),
+                        output,
+                        orientation(file),
+                        self.image_output(file.preset_name),
+                    )
+                    .with_all(self.copy_metadata(file, output))
+                    .copy_file_modification_date(file.file_full_path(), output),
+            ));
+        }
+        commands.push((
+            "imagemagick",
+            self.resize_with_imagemagick(
+                file,
+                runner(),
+                file.file_full_path(),
+                None,
+                &self.profiles(file, file.exif.has_color_profile()),
+                output,
+            )
+            .with_all(self.copy_metadata(file, output))
+            .copy_file_modification_date(file.file_full_path(), output),
+        ));
+        commands
     }
 
-    fn define_input_and_output_paths(&self) -> String {
-        let output_filepath = self
-            .file
-            .relative_path_with_file_stem_and_a_given_extension("jpg");
-        format!(
-            r#"mkdir -p "{}"
-input="{}"
-output="{}""#,
-            self.file.relative_path(),
-            self.file.file_full_path(),
-            output_filepath
-        )
+    /// The video of a motion photo, extracted along the still of a single preset, then converted
+    /// by the video presets once the processor registers it.
+    fn extra_outputs(&self, file: &FileToBeProcessed) -> Vec<ExtraOutput> {
+        if !file.exif.is_motion_photo()
+            || self.motion_photo_preset.as_deref() != Some(file.preset_name)
+        {
+            return vec![];
+        }
+        let offset = file.exif.micro_video_offset.map(|offset| offset as u64);
+        let temporary_path = motion_photo_video_temporary_path(file);
+        let command = CommandRunner::build(file.output_folder, file.shutdown)
+            .with_timeout(file.timeout)
+            .extract_motion_photo_video(file.file_full_path(), &temporary_path, offset);
+        vec![ExtraOutput {
+            name: "motion photo video",
+            path: motion_photo_video_path(file),
+            temporary_path,
+            command,
+        }]
+    }
+
+    fn parse_result(&self, file: &FileToBeProcessed, result: ProcessingResult) -> ProcessingResult {
+        let result = match file.exif.megapixels() {
+            Some(megapixels) if result.has_succeeded && !result.pass_through => {
+                result.with_metrics(ProcessingMetrics::Image(ImageMetrics { megapixels }))
+            }
+            _ => result,
+        };
+        let output = file.output_path("jpg");
+        let result = with_placeholder(file, result, &output);
+        let result = if self.perceptual_hash_preset.as_deref() == Some(file.preset_name) {
+            with_perceptual_hash(file, result, &output)
+        } else {
+            result
+        };
+        let motion_photo_video = motion_photo_video_path(file);
+        if result.extra_outputs.contains(&motion_photo_video) {
+            return result.with_motion_photo_video(motion_photo_video);
+        }
+        result
+    }
+
+    fn estimate_output_size(&self, file: &FileToBeProcessed) -> Option<u64> {
+        if self.is_compatible(file) {
+            return u64::try_from(file.file.size).ok();
+        }
+        let (width, height) = (file.exif.image_width?, file.exif.image_height?);
+        let size = resize_size(file.preset_name);
+        let pixels = match self.crop(file.preset_name) {
+            Some(crop) => {
+                let (width, height) = crop.dimensions(size);
+                width as f64 * height as f64
+            }
+            // The smallest side is resized to the preset size, keeping the aspect ratio.
+            None => {
+                let scale = size as f64 / width.min(height);
+                width * height * scale * scale
+            }
+        };
+        let size = (pixels * JPEG_BYTES_PER_PIXEL) as u64;
+        match self.encoding(file.preset_name).max_size() {
+            Some(max_size) => Some(size.min(max_size)),
+            None => Some(size),
+        }
+    }
+
+    fn estimate_duration(&self, file: &FileToBeProcessed, throughput: &Throughput) -> Option<Duration> {
+        if self.is_compatible(file) {
+            return Some(Duration::ZERO);
+        }
+        let seconds = file.exif.megapixels()? / throughput.megapixels_per_second;
+        Duration::try_from_secs_f64(seconds).ok()
     }
+}
+
+/// Whether the preset converts images, unlike the original one mirroring them and the live one
+/// only converting the clips of Live Photos.
+fn is_image_preset(preset_name: &str) -> bool {
+    preset_name != ORIGINAL_PRESET_NAME && preset_name != LIVE_PRESET_NAME
+}
 
-    fn convert_image_preview(&self) -> &str {
-        r#"convert "$input" -resize 1280x1280^ "$output""#
+pub fn resize_size(preset_name: &str) -> u32 {
+    match preset_name {
+        "thumbnail" => 400,
+        _ => 1280,
     }
+}
 
-    fn convert_image_thumbnail(&self) -> &str {
-        r#"convert "$input" -resize 400x400^ "$output""#
+/// EXIF orientation of the source, from 1 for normal to 8.
+fn orientation(file: &FileToBeProcessed) -> u8 {
+    match file.exif.orientation {
+        Some(orientation) if (1.0..=8.0).contains(&orientation) => orientation as u8,
+        _ => 1,
     }
+}
+
+fn is_native(file: &FileToBeProcessed) -> bool {
+    NATIVE_MIME_TYPES.contains(&file.exif.mime_type.as_str())
+}
 
-    fn copy_file_modification_date(&self) -> &str {
-        r#"touch -r "$input" "$output""#
+fn is_raw(file: &FileToBeProcessed) -> bool {
+    RAW_EXTENSIONS.contains(&file.file.extension.as_str())
+}
+
+/// Only the first frame is read, so multi-page images like TIFFs render their primary page and
+/// HDR photos their SDR rendition, without the gain map. The pixels are rotated according to
+/// `orientation`, or to the one in the input when unset, resized to cover `width` x `height` and
+/// converted to the last of `profiles`. The other metadata is stripped, to be copied afterwards,
+/// and the output written with the `encoding` options.
+fn resize(
+    input: impl AsRef<Path>,
+    (width, height): (u32, u32),
+    orientation: Option<u8>,
+    profiles: &[&Path],
+    encoding: &[String],
+    output: impl AsRef<Path>,
+) -> CommandLine {
+    let command = CommandLine::new("convert").arg(format!("{}[0]", input.as_ref().display()));
+    let command = match orientation {
+        Some(orientation) => command.args(["-orient", imagemagick_orientation(orientation)]),
+        None => command,
+    };
+    let command = command
+        .args(["-auto-orient", "+profile", "!icc,*"])
+        .args(["-resize", &format!("{width}x{height}^")]);
+    profiles
+        .iter()
+        .fold(command, |command, profile| {
+            command.arg("-profile").arg(profile)
+        })
+        .args(encoding)
+        .arg(output)
+}
+
+/// Maps the PQ or HLG luminance of an HDR image to SDR with the Hable curve, in the primaries of
+/// the color space, and writes it as a PNG to be resized and encoded by ImageMagick.
+fn tone_map(file: &FileToBeProcessed, color_space: ColorSpace, output: &Path) -> CommandLine {
+    let filters = [
+        "zscale=t=linear:npl=203".to_owned(),
+        "format=gbrpf32le".to_owned(),
+        format!("zscale=p={}", color_space.zscale_primaries()),
+        "tonemap=tonemap=hable:desat=0".to_owned(),
+        "zscale=t=iec61966-2-1:m=bt709:r=full".to_owned(),
+        "format=rgb24".to_owned(),
+    ];
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y"])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-frames:v", "1", "-vf", &filters.join(",")])
+        .args(["-c:v", "png", "-f", "image2"])
+        .arg(output)
+}
+
+fn imagemagick_orientation(orientation: u8) -> &'static str {
+    match orientation {
+        2 => "top-right",
+        3 => "bottom-right",
+        4 => "bottom-left",
+        5 => "left-top",
+        6 => "right-top",
+        7 => "right-bottom",
+        8 => "left-bottom",
+        _ => "top-left",
     }
 }
+
+/// Decodes a RAW file with the camera white balance to a TIFF on the standard output.
+fn decode_raw(file: &FileToBeProcessed) -> CommandLine {
+    CommandLine::new("dcraw")
+        .args(["-c", "-w", "-T"])
+        .arg(file.file_full_path())
+}
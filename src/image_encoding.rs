@@ -0,0 +1,164 @@
+use crate::config::{parse_preset_values, Config};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Quality of the native encoder when the preset sets none, the one ImageMagick picks when it
+/// cannot estimate the one of the source.
+pub const DEFAULT_JPEG_QUALITY: u8 = 92;
+
+/// Resolution of the color channels of a JPEG, relative to the brightness one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl ChromaSubsampling {
+    /// Sampling factor, as ImageMagick writes it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChromaSubsampling::Yuv420 => "4:2:0",
+            ChromaSubsampling::Yuv422 => "4:2:2",
+            ChromaSubsampling::Yuv444 => "4:4:4",
+        }
+    }
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.replace(':', "").as_str() {
+            "420" => Ok(ChromaSubsampling::Yuv420),
+            "422" => Ok(ChromaSubsampling::Yuv422),
+            "444" => Ok(ChromaSubsampling::Yuv444),
+            _ => Err(format!(
+                "unknown chroma subsampling {s}, expected 420, 422 or 444"
+            )),
+        }
+    }
+}
+
+/// How the JPEG outputs of a preset are encoded. Unset values are left to the encoder, which
+/// subsamples the chroma below quality 90.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JpegEncoding {
+    pub quality: Option<u8>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub progressive: bool,
+    /// Leaves out the metadata of the source and its sidecars, and the sRGB profile.
+    pub strip_metadata: bool,
+    /// Largest output, the quality being lowered until it fits.
+    pub max_size_kb: Option<u64>,
+}
+
+/// Lists every parameter of the encoding, for the command log.
+impl Display for JpegEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.quality {
+            Some(quality) => write!(f, "quality={quality}")?,
+            None => write!(f, "quality={DEFAULT_JPEG_QUALITY}")?,
+        }
+        match self.chroma_subsampling {
+            Some(chroma_subsampling) => write!(f, " subsampling={}", chroma_subsampling.name())?,
+            None => write!(f, " subsampling=auto")?,
+        }
+        write!(
+            f,
+            " progressive={} strip={}",
+            self.progressive, self.strip_metadata
+        )?;
+        match self.max_size() {
+            Some(max_size) => write!(f, " max_bytes={max_size}"),
+            None => write!(f, " max_bytes=none"),
+        }
+    }
+}
+
+impl JpegEncoding {
+    /// Whether a source JPEG of `size` bytes can be used as is.
+    pub fn accepts_source(&self, size: u64) -> bool {
+        self.quality.is_none()
+            && self.chroma_subsampling.is_none()
+            && !self.progressive
+            && !self.strip_metadata
+            && !matches!(self.max_size(), Some(max_size) if size > max_size)
+    }
+
+    /// Largest output in bytes.
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size_kb.map(|max_size_kb| max_size_kb * 1024)
+    }
+
+    /// ImageMagick options writing the output, `-strip` also removing the color profile.
+    pub fn imagemagick_args(&self, keep_profile: bool) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(quality) = self.quality {
+            args.extend(["-quality".to_owned(), quality.to_string()]);
+        }
+        if let Some(chroma_subsampling) = self.chroma_subsampling {
+            args.extend([
+                "-sampling-factor".to_owned(),
+                chroma_subsampling.name().to_owned(),
+            ]);
+        }
+        if self.progressive {
+            args.extend(["-interlace".to_owned(), "JPEG".to_owned()]);
+        }
+        // ImageMagick searches the highest quality fitting the size itself.
+        if let Some(max_size_kb) = self.max_size_kb {
+            args.extend(["-define".to_owned(), format!("jpeg:extent={max_size_kb}kb")]);
+        }
+        if self.strip_metadata && !keep_profile {
+            args.push("-strip".to_owned());
+        }
+        args
+    }
+}
+
+/// The JPEG encoding settings of all the presets.
+pub struct JpegEncodings {
+    qualities: HashMap<String, u8>,
+    chroma_subsamplings: HashMap<String, ChromaSubsampling>,
+    progressive: HashMap<String, bool>,
+    strip_metadata: HashMap<String, bool>,
+    max_sizes_kb: HashMap<String, u64>,
+}
+
+impl JpegEncodings {
+    pub fn new(config: &Config) -> JpegEncodings {
+        let mut qualities = parse_preset_values("IMAGE_QUALITIES", &config.image_qualities);
+        qualities.retain(|preset_name, quality| {
+            let is_valid = (1..=100).contains(quality);
+            if !is_valid {
+                error!("Ignoring IMAGE_QUALITIES entry {preset_name}={quality}, expected 1 to 100");
+            }
+            is_valid
+        });
+        JpegEncodings {
+            qualities,
+            chroma_subsamplings: parse_preset_values(
+                "IMAGE_CHROMA_SUBSAMPLINGS",
+                &config.image_chroma_subsamplings,
+            ),
+            progressive: parse_preset_values("IMAGE_PROGRESSIVE", &config.image_progressive),
+            strip_metadata: parse_preset_values(
+                "IMAGE_STRIP_METADATA",
+                &config.image_strip_metadata,
+            ),
+            max_sizes_kb: parse_preset_values("IMAGE_MAX_SIZES_KB", &config.image_max_sizes_kb),
+        }
+    }
+
+    pub fn preset(&self, preset_name: &str) -> JpegEncoding {
+        JpegEncoding {
+            quality: self.qualities.get(preset_name).copied(),
+            chroma_subsampling: self.chroma_subsamplings.get(preset_name).copied(),
+            progressive: self.progressive.get(preset_name) == Some(&true),
+            strip_metadata: self.strip_metadata.get(preset_name) == Some(&true),
+            max_size_kb: self.max_sizes_kb.get(preset_name).copied(),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::db::{self, LivePhoto, LivePhotoCandidate};
+use crate::exiftool::exiftool_on_file;
+use crate::original_converter::ORIGINAL_PRESET_NAME;
+use crate::time::now;
+use crate::AppContext;
+use anyhow::Result;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+/// Preset of the clips of Live Photos, which are not converted by the other video presets.
+pub const LIVE_PRESET_NAME: &str = "live";
+
+/// Pairs the stills and clips of Live Photos discovered by the scanner. Candidates share their
+/// folder and stem, and are confirmed by the content identifier Apple writes in both files. The
+/// rejected ones are remembered, so they are only read again once one of their files changes.
+pub async fn pair_live_photos(ctx: &AppContext) -> Result<usize> {
+    let candidates = db::get_live_photo_candidates(&ctx.db).await?;
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(ctx.config.scanner_threads)
+        .build()
+        .unwrap_or_else(|e| panic!("Failure initing threadpool: {e}"));
+    let checked: Vec<(LivePhotoCandidate, Option<String>)> = tokio::task::block_in_place(|| {
+        pool.install(|| {
+            candidates
+                .into_par_iter()
+                .map(|candidate| {
+                    let content_identifier = matching_content_identifier(
+                        &candidate.still_full_path,
+                        &candidate.clip_full_path,
+                    );
+                    (candidate, content_identifier)
+                })
+                .collect()
+        })
+    });
+    let mut live_photos = vec![];
+    let mut rejected = vec![];
+    for (candidate, content_identifier) in checked {
+        match content_identifier {
+            Some(content_identifier) => live_photos.push(LivePhoto {
+                still_full_path: candidate.still_full_path,
+                clip_full_path: candidate.clip_full_path,
+                content_identifier,
+                created_at: now(),
+            }),
+            None => rejected.push(candidate),
+        }
+    }
+    for live_photo in live_photos.iter() {
+        db::insert_live_photo(
+            &ctx.db,
+            live_photo,
+            &[LIVE_PRESET_NAME, ORIGINAL_PRESET_NAME],
+        )
+        .await?;
+    }
+    if !rejected.is_empty() {
+        db::upsert_rejected_live_photo_candidates(&ctx.db, &rejected).await?;
+    }
+    if !live_photos.is_empty() {
+        info!("Paired {} Live Photos.", live_photos.len());
+    }
+    Ok(live_photos.len())
+}
+
+fn matching_content_identifier(still_full_path: &str, clip_full_path: &str) -> Option<String> {
+    let content_identifier = |path: &str| match exiftool_on_file(path) {
+        Ok(exif) => exif.content_identifier,
+        Err(e) => {
+            debug!("Failure reading the content identifier of {path}: {e}");
+            None
+        }
+    };
+    let still_identifier = content_identifier(still_full_path)?;
+    (content_identifier(clip_full_path)? == still_identifier).then_some(still_identifier)
+}
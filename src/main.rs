@@ -1,13 +1,37 @@
 extern crate serde;
 extern crate serde_json;
 
+mod animated_image_converter;
+mod api;
+mod audio_converter;
+mod color;
+mod command_runner;
 mod config;
+mod converter;
+mod crop;
 mod db;
+mod duplicates;
 mod errors;
 mod exiftool;
+mod file_copy;
 mod image_converter;
+mod image_encoding;
+mod live_photo;
+mod metrics;
+mod motion_photo;
+mod native_image;
+mod original_converter;
+mod perceptual_hash;
+mod placeholder;
+mod plan;
+mod priority;
 mod processor;
+mod raw_preview;
+mod requeue;
 mod scanner;
+mod shutdown;
+mod sidecar;
+mod timeout;
 mod time;
 mod video_converter;
 
@@ -16,18 +40,23 @@ use env_logger::Env;
 #[macro_use]
 extern crate log;
 
-use crate::config::Config;
+use crate::config::{Command, Config};
 use anyhow::Context;
 use clap::Parser;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::task::JoinSet;
 
 #[derive(Clone)]
 pub struct AppContext {
     config: Arc<Config>,
     db: PgPool,
+    shutdown: Shutdown,
+    metrics: Arc<Metrics>,
 }
 
 // references: https://github.com/launchbadge/realworld-axum-sqlx/tree/main
@@ -45,15 +74,32 @@ async fn main() -> anyhow::Result<()> {
     let ctx = AppContext {
         config: Arc::new(config),
         db,
+        shutdown: Shutdown::default(),
+        metrics: Arc::new(Metrics::new().context("could not register metrics")?),
     };
     sqlx::migrate!().run(&ctx.db).await?;
+    match &ctx.config.command {
+        Some(Command::Requeue(args)) => return requeue::run(&ctx, args).await,
+        Some(Command::Plan) => return plan::run(&ctx).await,
+        Some(Command::Duplicates(args)) => return duplicates::run(&ctx, args).await,
+        None => {}
+    }
+    tokio::spawn(shutdown::listen_for_signals(
+        ctx.shutdown.clone(),
+        Duration::from_secs(ctx.config.seconds_shutdown_grace_period),
+    ));
     let mut set = JoinSet::new();
     let scanner_ctx = ctx.clone();
     let processor_ctx = ctx.clone();
+    let api_ctx = ctx.clone();
     set.spawn(async move { scanner::run(&scanner_ctx).await });
-    set.spawn(async move { processor::run(&processor_ctx).await });
-    while let Some(res) = set.join_next().await {
-        let _idx = res.unwrap();
+    if ctx.config.enable_api {
+        set.spawn(async move { api::run(&api_ctx).await });
     }
-    Ok(())
+    // The processor decides when fixmylib exits: it returns once a shutdown is requested and
+    // the running conversions are done, then the scanner and the API are stopped.
+    let processor_result = tokio::spawn(async move { processor::run(&processor_ctx).await }).await?;
+    set.shutdown().await;
+    info!("Shutdown complete.");
+    processor_result
 }
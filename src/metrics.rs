@@ -0,0 +1,124 @@
+use crate::processor::ProcessingResult;
+use prometheus::{
+    exponential_buckets, linear_buckets, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Prometheus metrics of the scanner and the processor, served by the API on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    queue_depth: IntGaugeVec,
+    file_jobs: IntCounterVec,
+    conversion_duration: HistogramVec,
+    transcode_fps: HistogramVec,
+    scan_duration: Gauge,
+    scan_files: IntGauge,
+    scan_finished_at: IntGauge,
+    exiftool_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Metrics> {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("fixmylib".to_owned()), None)?,
+            queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "queue_depth",
+                    "Unfinished file jobs, including the ones waiting for a retry.",
+                ),
+                &["preset"],
+            )?,
+            file_jobs: IntCounterVec::new(
+                Opts::new(
+                    "file_jobs_total",
+                    "Processed file jobs by status: succeeded, failed, retrying, or pending when interrupted.",
+                ),
+                &["preset", "status"],
+            )?,
+            conversion_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "conversion_duration_seconds",
+                    "Duration of successful conversions, including failed attempts of other backends.",
+                )
+                .buckets(exponential_buckets(0.1, 2.0, 18)?),
+                &["media_type", "backend"],
+            )?,
+            transcode_fps: HistogramVec::new(
+                HistogramOpts::new(
+                    "transcode_fps",
+                    "Mean frames per second of successful video conversions.",
+                )
+                .buckets(linear_buckets(10.0, 20.0, 15)?),
+                &["backend"],
+            )?,
+            scan_duration: Gauge::new(
+                "scan_duration_seconds",
+                "Duration of the last finished file scan.",
+            )?,
+            scan_files: IntGauge::new(
+                "scan_discovered_files",
+                "Files discovered by the last finished file scan.",
+            )?,
+            scan_finished_at: IntGauge::new(
+                "scan_finished_timestamp_seconds",
+                "Unix time of the end of the last finished file scan.",
+            )?,
+            exiftool_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "exiftool_duration_seconds",
+                    "Duration of the exiftool calls extracting the metadata of a file.",
+                )
+                .buckets(exponential_buckets(0.01, 2.0, 12)?),
+            )?,
+        };
+        metrics.registry.register(Box::new(metrics.queue_depth.clone()))?;
+        metrics.registry.register(Box::new(metrics.file_jobs.clone()))?;
+        metrics.registry.register(Box::new(metrics.conversion_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.transcode_fps.clone()))?;
+        metrics.registry.register(Box::new(metrics.scan_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.scan_files.clone()))?;
+        metrics.registry.register(Box::new(metrics.scan_finished_at.clone()))?;
+        metrics.registry.register(Box::new(metrics.exiftool_duration.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn set_queue_depth(&self, preset_name: &str, depth: i64) {
+        self.queue_depth.with_label_values(&[preset_name]).set(depth);
+    }
+
+    pub fn record_file_job(&self, preset_name: &str, status: &str) {
+        self.file_jobs.with_label_values(&[preset_name, status]).inc();
+    }
+
+    pub fn record_conversion(&self, media_type: &str, backend: &str, result: &ProcessingResult) {
+        if !result.has_succeeded {
+            return;
+        }
+        self.conversion_duration
+            .with_label_values(&[media_type, backend])
+            .observe(result.processing_seconds());
+        if let Some(fps) = result.fps() {
+            self.transcode_fps
+                .with_label_values(&[backend])
+                .observe(fps as f64);
+        }
+    }
+
+    pub fn record_scan(&self, duration: Duration, discovered_files: i64, finished_at: i64) {
+        self.scan_duration.set(duration.as_secs_f64());
+        self.scan_files.set(discovered_files);
+        self.scan_finished_at.set(finished_at);
+    }
+
+    pub fn record_exiftool(&self, duration: Duration) {
+        self.exiftool_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> prometheus::Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
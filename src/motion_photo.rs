@@ -0,0 +1,177 @@
+use crate::db::File;
+use crate::processor::FileToBeProcessed;
+use crate::time::now;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Folder of the output tree where the videos embedded in motion photos are extracted to, before
+/// being converted by the video presets like any other file.
+const DERIVATIVES_FOLDER: &str = ".derivatives";
+
+/// Where the video embedded in a motion photo is extracted to, mirroring the folder of the photo.
+pub fn motion_photo_video_path(file: &FileToBeProcessed) -> PathBuf {
+    derivatives_folder_path(file).join(format!("{}.motion.mp4", file.file_stem()))
+}
+
+/// Hidden file the video is written to before being renamed to [`motion_photo_video_path`].
+pub fn motion_photo_video_temporary_path(file: &FileToBeProcessed) -> PathBuf {
+    derivatives_folder_path(file).join(format!(".{}.fixmylib-tmp.motion.mp4", file.file_stem()))
+}
+
+fn derivatives_folder_path(file: &FileToBeProcessed) -> PathBuf {
+    Path::new(file.output_folder)
+        .join(DERIVATIVES_FOLDER)
+        .join(file.relative_folder_path())
+}
+
+/// Brands of the `ftyp` box starting HEIF images, such as HEIC motion photos, rather than videos.
+const HEIF_BRANDS: [&[u8; 4]; 12] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs", b"mif1", b"msf1",
+    b"avif", b"avis",
+];
+
+/// Writes the MP4 appended to a motion photo to `to` and returns its size. The video starts
+/// `offset` bytes before the end of the file when the photo tells, otherwise at the first `ftyp`
+/// box after the start of the photo.
+pub fn extract_motion_photo_video(from: &Path, to: &Path, offset: Option<u64>) -> io::Result<u64> {
+    let data = std::fs::read(from)?;
+    let start = offset
+        .and_then(|offset| data.len().checked_sub(usize::try_from(offset).ok()?))
+        .filter(|start| is_ftyp_box(&data, *start))
+        .or_else(|| (1..data.len()).find(|start| is_ftyp_box(&data, *start)))
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no embedded MP4 video was found")
+        })?;
+    if let Some(folder) = to.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    std::fs::write(to, &data[start..])?;
+    Ok((data.len() - start) as u64)
+}
+
+/// The file of a video extracted from a motion photo, linked to the folder of the photo so its
+/// outputs are written next to the photo ones.
+pub fn motion_photo_video_file(photo: &File, video_path: &Path) -> io::Result<File> {
+    let size = std::fs::metadata(video_path)?.len();
+    let stem = format!("{}.motion", photo.stem);
+    let name = format!("{stem}.mp4");
+    Ok(File {
+        file_full_path: video_path.to_string_lossy().into_owned(),
+        folder_full_path: photo.folder_full_path.clone(),
+        path: Path::new(&photo.path)
+            .with_file_name(&name)
+            .to_string_lossy()
+            .into_owned(),
+        size: size as i64,
+        stem,
+        extension: "mp4".to_owned(),
+        name,
+        has_been_processed: false,
+        created_at: now(),
+        updated_at: now(),
+        file_modified_at: photo.file_modified_at,
+        filescan_job_id: photo.filescan_job_id,
+        mime_type: Some("video/mp4".to_owned()),
+        captured_at: photo.captured_at,
+    })
+}
+
+/// An MP4 starts with a `ftyp` box: a small size, the box type and an alphanumeric brand, which
+/// is not one of a HEIF image.
+fn is_ftyp_box(data: &[u8], start: usize) -> bool {
+    let Some(header) = data.get(start..start + 12) else {
+        return false;
+    };
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let brand = &header[8..12];
+    (16..=256).contains(&size)
+        && &header[4..8] == b"ftyp"
+        && brand
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b' ')
+        && !HEIF_BRANDS.iter().any(|heif_brand| brand == *heif_brand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `ftyp` box of 24 bytes followed by some data.
+    const MP4: &[u8] = b"\0\0\0\x18ftypmp42\0\0\0\0isommp42\0\0\0\x08free";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe1 still image \xff\xd9";
+    /// A HEIC image, starting with its own `ftyp` box.
+    const HEIC: &[u8] = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic\0\0\0\x08meta still image";
+
+    /// Extracts the video of a photo made of `data` to a temporary folder, returning what was
+    /// written.
+    fn extract(name: &str, data: &[u8], offset: Option<u64>) -> io::Result<Vec<u8>> {
+        let folder = std::env::temp_dir()
+            .join(format!("fixmylib-motion-photo-{}", std::process::id()))
+            .join(name);
+        std::fs::create_dir_all(&folder)?;
+        let (photo, video) = (folder.join("photo.jpg"), folder.join("video.mp4"));
+        std::fs::write(&photo, data)?;
+        let result = extract_motion_photo_video(&photo, &video, offset).and_then(|size| {
+            let video = std::fs::read(&video)?;
+            assert_eq!(size, video.len() as u64);
+            Ok(video)
+        });
+        std::fs::remove_dir_all(&folder)?;
+        result
+    }
+
+    #[test]
+    fn finds_the_video_after_the_photo() {
+        let photo = [JPEG, MP4].concat();
+        assert_eq!(extract("search", &photo, None).unwrap(), MP4);
+    }
+
+    #[test]
+    fn uses_the_offset_from_the_end_of_the_file() {
+        // The JPEG contains a valid looking box, skipped thanks to the offset.
+        let photo = [&MP4[..12], JPEG, MP4].concat();
+        let offset = Some(MP4.len() as u64);
+        assert_eq!(extract("offset", &photo, offset).unwrap(), MP4);
+    }
+
+    #[test]
+    fn searches_the_video_when_the_offset_is_wrong() {
+        let photo = [JPEG, MP4].concat();
+        for offset in [MP4.len() as u64 + 3, photo.len() as u64 + 1, u64::MAX] {
+            assert_eq!(extract("wrong-offset", &photo, Some(offset)).unwrap(), MP4);
+        }
+    }
+
+    #[test]
+    fn skips_the_ftyp_box_of_a_heic_photo() {
+        let photo = [HEIC, MP4].concat();
+        assert_eq!(extract("heic", &photo, None).unwrap(), MP4);
+        let error = extract("heic-only", HEIC, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn fails_without_ftyp_box() {
+        let error = extract("no-ftyp", JPEG, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn fails_on_a_truncated_ftyp_box() {
+        let photo = [JPEG, &MP4[..10]].concat();
+        let error = extract("truncated", &photo, Some(10)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn ftyp_box_needs_a_plausible_size_and_brand() {
+        assert!(is_ftyp_box(MP4, 0));
+        assert!(!is_ftyp_box(MP4, 1));
+        assert!(!is_ftyp_box(b"\0\0\0\x08ftypmp42", 0));
+        assert!(!is_ftyp_box(b"\0\0\x10\0ftypmp42", 0));
+        assert!(!is_ftyp_box(b"\0\0\0\x18ftyp\0\xff\0\0", 0));
+        assert!(!is_ftyp_box(b"\0\0\0\x18moovmp42", 0));
+        assert!(!is_ftyp_box(HEIC, 0));
+        assert!(!is_ftyp_box(b"\0\0\0\x18ftypmif1", 0));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::color::{convert_pixels, ColorSpace};
+use crate::crop::{crop_position, Crop};
+use crate::image_encoding::{ChromaSubsampling, JpegEncoding, DEFAULT_JPEG_QUALITY};
+use fast_image_resize as fr;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::io::Reader as ImageReader;
+use image::{imageops, DynamicImage, ImageDecoder, ImageFormat, ImageResult, RgbImage};
+use jpeg_encoder::{Encoder, SamplingFactor};
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufReader};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::Instant;
+
+/// MIME types decoded in process, the other images being left to ImageMagick.
+pub const NATIVE_MIME_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+
+/// Geometry and encoding of a JPEG written in process.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageOutput {
+    /// Smallest side of the output.
+    pub size: u32,
+    pub crop: Option<Crop>,
+    pub color_space: ColorSpace,
+    pub encoding: JpegEncoding,
+}
+
+impl ImageOutput {
+    /// Dimensions the image is resized to, covering the crop when there is one.
+    fn resize_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let crop = match self.crop {
+            Some(crop) => crop,
+            None => return output_dimensions(width, height, self.size),
+        };
+        let (crop_width, crop_height) = crop.dimensions(self.size);
+        let scale = (crop_width as f64 / width.max(1) as f64)
+            .max(crop_height as f64 / height.max(1) as f64);
+        let scaled = |side: u32, minimum: u32| ((side as f64 * scale).round() as u32).max(minimum);
+        (scaled(width, crop_width), scaled(height, crop_height))
+    }
+
+    /// sRGB is the default of viewers, so stripped outputs can leave out its profile.
+    fn icc_profile(&self) -> Option<&'static [u8]> {
+        if self.encoding.strip_metadata && self.color_space == ColorSpace::Srgb {
+            None
+        } else {
+            Some(self.color_space.icc_profile())
+        }
+    }
+}
+
+/// Lists every parameter of the output, for the command log.
+impl Display for ImageOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "size={}", self.size)?;
+        match self.crop {
+            Some(crop) => write!(f, " crop={} ratio={}", crop.mode, crop.aspect_ratio)?,
+            None => write!(f, " crop=none")?,
+        }
+        write!(f, " color={} {}", self.color_space, self.encoding)
+    }
+}
+
+/// Resizes an image to the `output` geometry, rotates it according to the EXIF `orientation`,
+/// converts it to the color space of the output and writes it to `to` as a JPEG with the profile
+/// of the color space, returning its dimensions. The other metadata is copied by exiftool
+/// afterwards. Fails with [`io::ErrorKind::TimedOut`] once past the `deadline`.
+pub fn resize_natively(
+    from: &Path,
+    to: &Path,
+    orientation: u8,
+    output: &ImageOutput,
+    deadline: Option<Instant>,
+) -> io::Result<(u32, u32)> {
+    let source = std::fs::read(from)?;
+    // The output geometry applies once the image is rotated.
+    let is_transposed = (5..=8).contains(&orientation);
+    let resize_dimensions = |width, height| {
+        if is_transposed {
+            let (height, width) = output.resize_dimensions(height, width);
+            (width, height)
+        } else {
+            output.resize_dimensions(width, height)
+        }
+    };
+    let (image, profile) = decode(&source, resize_dimensions)?;
+    check_deadline(deadline)?;
+    let (width, height) = resize_dimensions(image.width(), image.height());
+    let mut resized = resize_rgb(image, width, height)?;
+    convert_pixels(&mut resized, profile.as_deref(), output.color_space)?;
+    check_deadline(deadline)?;
+    // Rotating after resizing handles fewer pixels.
+    let resized = RgbImage::from_raw(width, height, resized)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the image is truncated"))?;
+    let image = crop(orient(resized, orientation), output);
+    std::fs::write(
+        to,
+        encode_jpeg(&image, &output.encoding, output.icc_profile(), deadline)?,
+    )?;
+    Ok(image.dimensions())
+}
+
+/// Crops and encodes the image at `path`, written by another tool at the dimensions of the
+/// output and in its color space, to a JPEG in place. Images larger than the output are resized
+/// first. Fails with [`io::ErrorKind::TimedOut`] once past the `deadline`.
+pub fn encode_natively(
+    path: &Path,
+    output: &ImageOutput,
+    deadline: Option<Instant>,
+) -> io::Result<(u32, u32)> {
+    let source = std::fs::read(path)?;
+    let (image, _) = decode(&source, |width, height| {
+        output.resize_dimensions(width, height)
+    })?;
+    check_deadline(deadline)?;
+    let (width, height) = output.resize_dimensions(image.width(), image.height());
+    let image = if (width, height) == (image.width(), image.height()) {
+        image.into_rgb8()
+    } else {
+        RgbImage::from_raw(width, height, resize_rgb(image, width, height)?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the image is truncated"))?
+    };
+    let image = crop(image, output);
+    std::fs::write(
+        path,
+        encode_jpeg(&image, &output.encoding, output.icc_profile(), deadline)?,
+    )?;
+    Ok(image.dimensions())
+}
+
+/// Decodes an image with its ICC profile. JPEGs are decoded at the smallest power of two scale
+/// larger than the dimensions returned by `resize_dimensions`, which is most of the time spent on
+/// large photos.
+fn decode(
+    source: &[u8],
+    resize_dimensions: impl Fn(u32, u32) -> (u32, u32),
+) -> io::Result<(DynamicImage, Option<Vec<u8>>)> {
+    let cursor = || io::Cursor::new(source);
+    let reader = ImageReader::new(cursor()).with_guessed_format()?;
+    match reader.format() {
+        Some(ImageFormat::Jpeg) => {
+            let mut decoder = JpegDecoder::new(BufReader::new(cursor())).map_err(invalid_data)?;
+            let (width, height) = decoder.dimensions();
+            let (width, height) = resize_dimensions(width, height);
+            decoder
+                .scale(clamp_u16(width), clamp_u16(height))
+                .map_err(invalid_data)?;
+            decode_with_profile(decoder)
+        }
+        Some(ImageFormat::Png) => {
+            decode_with_profile(PngDecoder::new(cursor()).map_err(invalid_data)?)
+        }
+        Some(ImageFormat::WebP) => {
+            decode_with_profile(WebPDecoder::new(cursor()).map_err(invalid_data)?)
+        }
+        _ => reader.decode().map(|image| (image, None)),
+    }
+    .map_err(invalid_data)
+}
+
+/// Keeps the part of the image chosen by the crop mode of the output, if any.
+fn crop(image: RgbImage, output: &ImageOutput) -> RgbImage {
+    let crop = match output.crop {
+        Some(crop) => crop,
+        None => return image,
+    };
+    let (width, height) = crop.dimensions(output.size);
+    let (width, height) = (width.min(image.width()), height.min(image.height()));
+    let (x, y) = crop_position(&image, width, height, crop.mode);
+    imageops::crop_imm(&image, x, y, width, height).to_image()
+}
+
+/// Encodes at the quality of the preset, or at the highest lower one fitting its maximum size,
+/// found by a binary search. The lowest quality is used when none fits. The search stops once past
+/// the `deadline`, as each step encodes the whole image.
+fn encode_jpeg(
+    image: &RgbImage,
+    encoding: &JpegEncoding,
+    profile: Option<&[u8]>,
+    deadline: Option<Instant>,
+) -> io::Result<Vec<u8>> {
+    let quality = encoding.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    let jpeg = encode_jpeg_at(image, quality, encoding, profile)?;
+    let max_size = match encoding.max_size() {
+        Some(max_size) if jpeg.len() as u64 > max_size => max_size,
+        _ => return Ok(jpeg),
+    };
+    let (mut lowest, mut highest) = (1, quality - 1);
+    let mut fitting = None;
+    while lowest <= highest {
+        check_deadline(deadline)?;
+        let middle = (lowest + highest) / 2;
+        let jpeg = encode_jpeg_at(image, middle, encoding, profile)?;
+        if jpeg.len() as u64 <= max_size {
+            fitting = Some(jpeg);
+            lowest = middle + 1;
+        } else {
+            highest = middle - 1;
+        }
+    }
+    match fitting {
+        Some(jpeg) => Ok(jpeg),
+        None => encode_jpeg_at(image, 1, encoding, profile),
+    }
+}
+
+fn check_deadline(deadline: Option<Instant>) -> io::Result<()> {
+    if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the deadline has passed",
+        ));
+    }
+    Ok(())
+}
+
+fn encode_jpeg_at(
+    image: &RgbImage,
+    quality: u8,
+    encoding: &JpegEncoding,
+    profile: Option<&[u8]>,
+) -> io::Result<Vec<u8>> {
+    let too_large = |_| io::Error::new(io::ErrorKind::InvalidInput, "the image is too large");
+    let width = u16::try_from(image.width()).map_err(too_large)?;
+    let height = u16::try_from(image.height()).map_err(too_large)?;
+    let mut jpeg = Vec::new();
+    let mut encoder = Encoder::new(&mut jpeg, quality);
+    if let Some(chroma_subsampling) = encoding.chroma_subsampling {
+        encoder.set_sampling_factor(match chroma_subsampling {
+            ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+            ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+        });
+    }
+    encoder.set_progressive(encoding.progressive);
+    if let Some(profile) = profile {
+        encoder.add_icc_profile(profile).map_err(invalid_data)?;
+    }
+    encoder
+        .encode(image.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+        .map_err(invalid_data)?;
+    Ok(jpeg)
+}
+
+/// Dimensions with the smallest side at `size`, keeping the aspect ratio.
+fn output_dimensions(width: u32, height: u32, size: u32) -> (u32, u32) {
+    let scale = size as f64 / width.min(height).max(1) as f64;
+    let scaled = |side: u32| ((side as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+fn resize_rgb(image: DynamicImage, width: u32, height: u32) -> io::Result<Vec<u8>> {
+    let non_zero = |side: u32| {
+        NonZeroU32::new(side)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the image is empty"))
+    };
+    let (source_width, source_height) = (non_zero(image.width())?, non_zero(image.height())?);
+    let source = fr::Image::from_vec_u8(
+        source_width,
+        source_height,
+        image.into_rgb8().into_raw(),
+        fr::PixelType::U8x3,
+    )
+    .map_err(invalid_data)?;
+    let mut destination = fr::Image::new(non_zero(width)?, non_zero(height)?, source.pixel_type());
+    fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3))
+        .resize(&source.view(), &mut destination.view_mut())
+        .map_err(invalid_data)?;
+    Ok(destination.into_vec())
+}
+
+/// Applies an EXIF orientation, from 1 for normal to 8, to the pixels.
+fn orient(image: RgbImage, orientation: u8) -> RgbImage {
+    match orientation {
+        2 => imageops::flip_horizontal(&image),
+        3 => imageops::rotate180(&image),
+        4 => imageops::flip_vertical(&image),
+        5 => imageops::flip_horizontal(&imageops::rotate90(&image)),
+        6 => imageops::rotate90(&image),
+        7 => imageops::flip_horizontal(&imageops::rotate270(&image)),
+        8 => imageops::rotate270(&image),
+        _ => image,
+    }
+}
+
+fn decode_with_profile<'a>(
+    mut decoder: impl ImageDecoder<'a>,
+) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    let profile = decoder.icc_profile();
+    Ok((DynamicImage::from_decoder(decoder)?, profile))
+}
+
+fn clamp_u16(side: u32) -> u16 {
+    u16::try_from(side).unwrap_or(u16::MAX)
+}
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
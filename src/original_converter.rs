@@ -0,0 +1,48 @@
+use crate::command_runner::CommandRunner;
+use crate::converter::{Converter, Throughput};
+use crate::processor::FileToBeProcessed;
+use std::path::Path;
+use std::time::Duration;
+
+pub const ORIGINAL_PRESET_NAME: &str = "original";
+
+/// Mirrors every input file, unchanged, into the output tree of the original preset.
+pub struct OriginalConverter;
+
+impl Converter for OriginalConverter {
+    fn name(&self) -> &'static str {
+        "original"
+    }
+
+    fn handles(&self, file: &FileToBeProcessed) -> bool {
+        file.preset_name == ORIGINAL_PRESET_NAME
+    }
+
+    /// The extension of the source, keeping its case.
+    fn output_extension(&self, file: &FileToBeProcessed) -> String {
+        Path::new(&file.file.name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().into_owned())
+            .unwrap_or_else(|| file.file.extension.clone())
+    }
+
+    fn commands(&self, file: &FileToBeProcessed, output: &Path) -> Vec<(&'static str, CommandRunner)> {
+        vec![(
+            "link",
+            CommandRunner::build(file.output_folder, file.shutdown)
+                .with_timeout(file.timeout)
+                .create_folder(file.output_folder_path())
+                .link_or_copy(file.file_full_path(), output)
+                .copy_file_modification_date(file.file_full_path(), output)
+                .pass_through(),
+        )]
+    }
+
+    fn estimate_output_size(&self, file: &FileToBeProcessed) -> Option<u64> {
+        u64::try_from(file.file.size).ok()
+    }
+
+    fn estimate_duration(&self, _file: &FileToBeProcessed, _throughput: &Throughput) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::processor::{FileToBeProcessed, ProcessingResult};
+use image::io::Reader as ImageReader;
+use std::io;
+use std::path::Path;
+
+/// Columns compared by the difference hash, one more than its bits per row.
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+
+/// Adds the difference hash of the image at `output` to the result of its conversion. A failure
+/// only leaves the file without hash.
+pub fn with_perceptual_hash(
+    file: &FileToBeProcessed,
+    result: ProcessingResult,
+    output: &Path,
+) -> ProcessingResult {
+    if !result.has_succeeded {
+        return result;
+    }
+    match compute_dhash(output) {
+        Ok(hash) => result.with_perceptual_hash(hash),
+        Err(e) => {
+            error!(
+                "Failure computing the perceptual hash of {} from {}: {e}",
+                file.file_full_path(),
+                output.display()
+            );
+            result
+        }
+    }
+}
+
+/// Difference hash: each bit tells whether a pixel of the image shrunk to 9x8 in grayscale is
+/// brighter than its right neighbour. Resizing, recompressing or slightly editing a photo only
+/// flips a few of them.
+fn compute_dhash(path: &Path) -> io::Result<u64> {
+    let image = ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let sample = image.thumbnail_exact(HASH_WIDTH, HASH_HEIGHT).into_luma8();
+    let mut hash = 0u64;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            let is_brighter = sample.get_pixel(x, y).0[0] > sample.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | is_brighter as u64;
+        }
+    }
+    Ok(hash)
+}
+
+/// Bits differing between two hashes, 0 for identical images and around 32 for unrelated ones.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hamming_distance_counts_the_differing_bits() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(u64::MAX, u64::MAX), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(1 << 63, 1), 2);
+        assert_eq!(hamming_distance(0, u64::MAX), 64);
+    }
+
+    #[test]
+    fn hamming_distance_is_symmetric() {
+        let (a, b) = (0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210);
+        assert_eq!(hamming_distance(a, b), hamming_distance(b, a));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::processor::{FileToBeProcessed, ProcessingResult};
+use image::io::Reader as ImageReader;
+use std::io;
+use std::path::Path;
+
+/// Preset whose outputs the placeholders are computed from, the smallest ones.
+pub const PLACEHOLDER_PRESET: &str = "thumbnail";
+/// Side of the image the BlurHash is computed on, its details being blurred away anyway.
+const SAMPLE_SIZE: u32 = 32;
+/// Components along the longest side of the image, and along the other one.
+const COMPONENTS: (u32, u32) = (4, 3);
+
+/// BlurHash of an output, with its dimensions for the aspect ratio of the placeholder.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Placeholder {
+    pub blurhash: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Adds the placeholder of the thumbnail at `output` to the result of its conversion. A failure
+/// only leaves the file without placeholder.
+pub fn with_placeholder(
+    file: &FileToBeProcessed,
+    result: ProcessingResult,
+    output: &Path,
+) -> ProcessingResult {
+    if !result.has_succeeded || file.preset_name != PLACEHOLDER_PRESET {
+        return result;
+    }
+    match compute_placeholder(output) {
+        Ok(placeholder) => result.with_placeholder(placeholder),
+        Err(e) => {
+            error!(
+                "Failure computing the placeholder of {}: {e}",
+                output.display()
+            );
+            result
+        }
+    }
+}
+
+fn compute_placeholder(path: &Path) -> io::Result<Placeholder> {
+    let image = ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let (width, height) = (image.width(), image.height());
+    let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).into_rgba8();
+    let (components_x, components_y) = if width >= height {
+        COMPONENTS
+    } else {
+        (COMPONENTS.1, COMPONENTS.0)
+    };
+    let blurhash = blurhash::encode(
+        components_x,
+        components_y,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Placeholder {
+        blurhash,
+        width,
+        height,
+    })
+}
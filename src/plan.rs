@@ -0,0 +1,235 @@
+use crate::converter::{Converter, ConverterRegistry, Throughput};
+use crate::db::{self, File, FileJob};
+use crate::exiftool::{exiftool_on_file, Exiftool, ExiftoolError};
+use crate::processor::{create_file_jobs_for_unprocessed_files, get_preset_names, FileToBeProcessed};
+use crate::timeout::TimeoutPolicy;
+use crate::{scanner, AppContext};
+use anyhow::Result;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Assumed conversion speed on a single thread while a preset has no successful jobs yet.
+const DEFAULT_THROUGHPUT: Throughput = Throughput {
+    megapixels_per_second: 20.0,
+    fps: 60.0,
+};
+
+/// Files a converter would process for a preset, with the estimates of their outputs.
+#[derive(Debug, Default)]
+struct ConverterPlan {
+    threads: usize,
+    files: u64,
+    output_bytes: u64,
+    files_without_size: u64,
+    seconds: f64,
+    files_without_duration: u64,
+}
+
+impl ConverterPlan {
+    fn add(&mut self, converter: &dyn Converter, file: &FileToBeProcessed, throughput: &Throughput) {
+        self.files += 1;
+        match converter.estimate_output_size(file) {
+            Some(bytes) => self.output_bytes += bytes,
+            None => self.files_without_size += 1,
+        }
+        match converter.estimate_duration(file, throughput) {
+            Some(duration) => self.seconds += duration.as_secs_f64(),
+            None => self.files_without_duration += 1,
+        }
+    }
+
+    /// Conversions of a converter run in parallel on its threads.
+    fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.seconds / self.threads.max(1) as f64)
+    }
+}
+
+#[derive(Debug)]
+struct PresetPlan {
+    preset_name: String,
+    throughput: Throughput,
+    measured_megapixels_per_second: bool,
+    measured_fps: bool,
+    converters: BTreeMap<&'static str, ConverterPlan>,
+    skipped: BTreeMap<String, u64>,
+}
+
+impl PresetPlan {
+    fn skip(&mut self, reason: impl Into<String>, count: u64) {
+        if count > 0 {
+            *self.skipped.entry(reason.into()).or_default() += count;
+        }
+    }
+
+    fn output_bytes(&self) -> u64 {
+        self.converters.values().map(|c| c.output_bytes).sum()
+    }
+
+    /// Converters run one after the other within a batch, and presets one after the other.
+    fn duration(&self) -> Duration {
+        self.converters.values().map(|c| c.duration()).sum()
+    }
+
+    fn print(&self) {
+        println!("Preset {}:", self.preset_name);
+        if self.converters.is_empty() {
+            println!("  nothing to convert");
+        }
+        for (name, plan) in self.converters.iter() {
+            let mut unknowns = vec![];
+            if plan.files_without_size > 0 {
+                unknowns.push(format!("{} without size estimate", plan.files_without_size));
+            }
+            if plan.files_without_duration > 0 {
+                unknowns.push(format!("{} without duration estimate", plan.files_without_duration));
+            }
+            println!(
+                "  {name}: {} files, ~{}, ~{} on {} threads{}",
+                plan.files,
+                format_bytes(plan.output_bytes),
+                format_duration(plan.duration()),
+                plan.threads,
+                if unknowns.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", unknowns.join(", "))
+                }
+            );
+        }
+        for (reason, count) in self.skipped.iter() {
+            println!("  skipped: {count} {reason}");
+        }
+        println!(
+            "  throughput per thread: {:.1} megapixels/s ({}), {:.0} fps ({})",
+            self.throughput.megapixels_per_second,
+            if self.measured_megapixels_per_second { "measured" } else { "assumed" },
+            self.throughput.fps,
+            if self.measured_fps { "measured" } else { "assumed" },
+        );
+        println!(
+            "  estimated: ~{}, ~{}",
+            format_bytes(self.output_bytes()),
+            format_duration(self.duration())
+        );
+    }
+}
+
+/// Scans the input folder and creates the file jobs like the daemon does, then reports what
+/// the processor would convert, without converting anything.
+pub async fn run(ctx: &AppContext) -> Result<()> {
+    scanner::scan(ctx).await?;
+    let converters = ConverterRegistry::new(ctx);
+    let mut output_bytes = 0;
+    let mut duration = Duration::ZERO;
+    for preset_name in get_preset_names(ctx) {
+        create_file_jobs_for_unprocessed_files(ctx, &preset_name).await?;
+        let plan = plan_preset(ctx, &converters, &preset_name).await?;
+        plan.print();
+        output_bytes += plan.output_bytes();
+        duration += plan.duration();
+    }
+    println!(
+        "Total: ~{}, ~{}",
+        format_bytes(output_bytes),
+        format_duration(duration)
+    );
+    Ok(())
+}
+
+async fn plan_preset(
+    ctx: &AppContext,
+    converters: &ConverterRegistry,
+    preset_name: &str,
+) -> Result<PresetPlan> {
+    let history = db::get_conversion_throughput(&ctx.db, preset_name).await?;
+    let mut plan = PresetPlan {
+        preset_name: preset_name.to_owned(),
+        throughput: Throughput {
+            megapixels_per_second: history
+                .megapixels_per_second
+                .unwrap_or(DEFAULT_THROUGHPUT.megapixels_per_second),
+            fps: history.fps.unwrap_or(DEFAULT_THROUGHPUT.fps),
+        },
+        measured_megapixels_per_second: history.megapixels_per_second.is_some(),
+        measured_fps: history.fps.is_some(),
+        converters: BTreeMap::new(),
+        skipped: BTreeMap::new(),
+    };
+
+    let finished = db::count_finished_file_jobs(&ctx.db, preset_name).await?;
+    plan.skip("already converted", finished.succeeded as u64);
+    plan.skip("failed after all attempts", finished.failed as u64);
+    plan.skip("waiting for a retry", finished.waiting_for_retry as u64);
+
+    let throughput = plan.throughput.clone();
+    let timeout_policy = TimeoutPolicy::new(ctx);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(ctx.config.scanner_threads)
+        .build()
+        .unwrap_or_else(|e| panic!("Failure initing threadpool: {e}"));
+    let limit = 1000;
+    let mut offset = 0;
+    loop {
+        let files_and_jobs =
+            db::get_unprocessed_file_and_jobs(&ctx.db, preset_name, offset, limit).await?;
+        if files_and_jobs.is_empty() {
+            break;
+        }
+        offset += limit;
+
+        let exifs: Vec<(File, FileJob, Result<Exiftool, ExiftoolError>)> =
+            tokio::task::block_in_place(|| {
+                pool.install(|| {
+                    files_and_jobs
+                        .into_par_iter()
+                        .map(|(file, job)| {
+                            let exif = exiftool_on_file(&file.file_full_path);
+                            (file, job, exif)
+                        })
+                        .collect()
+                })
+            });
+        for (file, job, exif) in exifs {
+            let exif = match exif {
+                Ok(exif) => exif,
+                Err(e) => {
+                    debug!("Failure extracting exif data of {}: {e}", file.file_full_path);
+                    plan.skip("with unreadable metadata", 1);
+                    continue;
+                }
+            };
+            let timeout = timeout_policy.timeout(preset_name, &exif);
+            let file = FileToBeProcessed::new(ctx, preset_name, file, job, exif, timeout);
+            match converters.find(&file) {
+                Some((converter, threads)) => {
+                    let converter_plan = plan.converters.entry(converter.name()).or_default();
+                    converter_plan.threads = threads;
+                    converter_plan.add(converter, &file, &throughput);
+                }
+                None => plan.skip(format!("without converter for {}", file.exif.mime_type), 1),
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", units[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, m, _) => format!("{h}h {m}m"),
+    }
+}
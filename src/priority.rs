@@ -0,0 +1,183 @@
+use crate::db::File;
+use crate::image_converter::RAW_EXTENSIONS;
+use crate::AppContext;
+use std::path::Path;
+
+const PINNED_FOLDER_WEIGHT: i64 = 1 << 50;
+const MEDIA_TYPE_WEIGHT: i64 = 1 << 40;
+
+/// Extensions of images, besides the RAW ones.
+const IMAGE_EXTENSIONS: [&str; 11] = [
+    "jpg", "jpeg", "png", "heic", "heif", "gif", "webp", "tif", "tiff", "bmp", "avif",
+];
+const VIDEO_EXTENSIONS: [&str; 11] = [
+    "mov", "mp4", "m4v", "avi", "mkv", "3gp", "mts", "m2ts", "wmv", "mpg", "webm",
+];
+const AUDIO_EXTENSIONS: [&str; 9] = [
+    "m4a", "mp3", "aac", "flac", "wav", "ogg", "opus", "amr", "aiff",
+];
+
+/// Computes the priority of file jobs: files in pinned folders come first, then files by the
+/// configured media type order, and within those the most recently captured files first. Files
+/// exiftool has not run on yet are ranked by their modification date instead.
+pub struct JobPrioritizer {
+    pinned_folders: Vec<String>,
+    media_type_priority: Vec<String>,
+}
+
+impl JobPrioritizer {
+    pub fn new(ctx: &AppContext) -> JobPrioritizer {
+        JobPrioritizer {
+            pinned_folders: ctx
+                .config
+                .pinned_folders
+                .iter()
+                .filter(|folder| !folder.trim().is_empty())
+                .map(|folder| {
+                    Path::new(&ctx.config.input_folder)
+                        .join(folder)
+                        .to_string_lossy()
+                        .trim_end_matches('/')
+                        .to_owned()
+                })
+                .collect(),
+            media_type_priority: ctx
+                .config
+                .media_type_priority
+                .iter()
+                .map(|media_type| media_type.trim().to_lowercase())
+                .filter(|media_type| !media_type.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn priority(&self, file: &File) -> i64 {
+        let pinned = self.is_pinned(&file.folder_full_path) as i64;
+        let media_type_rank = media_type(file)
+            .and_then(|media_type| {
+                self.media_type_priority
+                    .iter()
+                    .position(|m| m == media_type)
+            })
+            .map(|position| (self.media_type_priority.len() - position) as i64)
+            .unwrap_or(0);
+        let recency = file
+            .captured_at
+            .unwrap_or(file.file_modified_at)
+            .assume_utc()
+            .unix_timestamp()
+            .max(0);
+
+        pinned * PINNED_FOLDER_WEIGHT + media_type_rank * MEDIA_TYPE_WEIGHT + recency
+    }
+
+    fn is_pinned(&self, folder_full_path: &str) -> bool {
+        self.pinned_folders.iter().any(|pinned| {
+            folder_full_path == pinned
+                || folder_full_path
+                    .strip_prefix(pinned.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+}
+
+/// Media type of a file, from its MIME type when exiftool already ran on it, otherwise guessed
+/// from its extension.
+fn media_type(file: &File) -> Option<&str> {
+    if let Some(mime_type) = &file.mime_type {
+        return mime_type.split('/').next();
+    }
+    let extension = file.extension.as_str();
+    if IMAGE_EXTENSIONS.contains(&extension) || RAW_EXTENSIONS.contains(&extension) {
+        Some("image")
+    } else if VIDEO_EXTENSIONS.contains(&extension) {
+        Some("video")
+    } else if AUDIO_EXTENSIONS.contains(&extension) {
+        Some("audio")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::types::time::{Date, PrimitiveDateTime, Time};
+    use uuid::Uuid;
+
+    fn date(year: i32) -> PrimitiveDateTime {
+        PrimitiveDateTime::new(Date::from_ordinal_date(year, 1).unwrap(), Time::MIDNIGHT)
+    }
+
+    fn file(path: &str, modified_at: i32, captured_at: Option<i32>) -> File {
+        let path = Path::new(path);
+        File {
+            file_full_path: path.to_string_lossy().into_owned(),
+            folder_full_path: path.parent().unwrap().to_string_lossy().into_owned(),
+            path: path.to_string_lossy().into_owned(),
+            size: 0,
+            stem: path.file_stem().unwrap().to_string_lossy().into_owned(),
+            extension: path.extension().unwrap().to_string_lossy().into_owned(),
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            has_been_processed: false,
+            created_at: date(2024),
+            updated_at: date(2024),
+            file_modified_at: date(modified_at),
+            filescan_job_id: Uuid::nil(),
+            mime_type: None,
+            captured_at: captured_at.map(date),
+        }
+    }
+
+    fn prioritizer(pinned_folders: &[&str], media_type_priority: &[&str]) -> JobPrioritizer {
+        JobPrioritizer {
+            pinned_folders: pinned_folders.iter().map(|f| f.to_string()).collect(),
+            media_type_priority: media_type_priority.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn ranks_recently_captured_files_first() {
+        let prioritizer = prioritizer(&[], &[]);
+        let old_photo_copied_recently = file("/in/a.jpg", 2024, Some(2010));
+        let recent_photo = file("/in/b.jpg", 2020, Some(2023));
+        assert!(
+            prioritizer.priority(&recent_photo) > prioritizer.priority(&old_photo_copied_recently)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_modification_date_without_capture_date() {
+        let prioritizer = prioritizer(&[], &[]);
+        let unprocessed = file("/in/a.jpg", 2022, None);
+        let captured = file("/in/b.jpg", 2024, Some(2021));
+        assert!(prioritizer.priority(&unprocessed) > prioritizer.priority(&captured));
+        assert_eq!(
+            prioritizer.priority(&unprocessed),
+            date(2022).assume_utc().unix_timestamp()
+        );
+    }
+
+    #[test]
+    fn ranks_pinned_folders_then_media_types_before_recency() {
+        let prioritizer = prioritizer(&["/in/pinned"], &["video", "image"]);
+        let pinned_old_photo = file("/in/pinned/a.jpg", 2000, Some(2000));
+        let recent_video = file("/in/b.mov", 2024, Some(2024));
+        let recent_photo = file("/in/c.jpg", 2024, Some(2024));
+        let recent_document = file("/in/d.pdf", 2024, Some(2024));
+        let priorities = [
+            prioritizer.priority(&pinned_old_photo),
+            prioritizer.priority(&recent_video),
+            prioritizer.priority(&recent_photo),
+            prioritizer.priority(&recent_document),
+        ];
+        assert!(priorities.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn pinned_folders_do_not_match_sibling_prefixes() {
+        let prioritizer = prioritizer(&["/in/pinned"], &[]);
+        assert!(prioritizer.is_pinned("/in/pinned/2023"));
+        assert!(!prioritizer.is_pinned("/in/pinned-not"));
+    }
+}
@@ -1,19 +1,26 @@
-use std::fmt::format;
-use crate::db::{File, FileJob};
+use crate::db::{File, FileJob, FilePerceptualHash, FilePlaceholder, MotionPhoto, PresetFiles};
 
 use crate::{db, AppContext};
 use anyhow::Result;
 
-use std::time::Duration;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use csv::Writer;
 
 use sqlx::types::time::PrimitiveDateTime;
 
+use crate::converter::ConverterRegistry;
 use crate::exiftool::{exiftool_on_file, Exiftool};
-use crate::image_converter::ImageConverterProcessor;
+use crate::live_photo::LIVE_PRESET_NAME;
+use crate::motion_photo::motion_photo_video_file;
+use crate::original_converter::ORIGINAL_PRESET_NAME;
+use crate::placeholder::Placeholder;
+use crate::priority::JobPrioritizer;
+use crate::requeue::file_job_status;
 use crate::time::{now, Ticker};
-use crate::video_converter::VideoConverterProcessor;
-use subprocess::{Exec, ExitStatus, Redirection};
+use crate::shutdown::Shutdown;
+use crate::timeout::TimeoutPolicy;
 use tokio::time::sleep;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -21,8 +28,14 @@ pub struct VideoMetrics {
     pub fps: u32,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImageMetrics {
+    pub megapixels: f64,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum ProcessingMetrics {
+    Image(ImageMetrics),
     Video(VideoMetrics),
 }
 
@@ -31,6 +44,18 @@ pub struct ProcessingResult {
     pub command: String,
     pub command_log: String,
     pub has_succeeded: bool,
+    pub interrupted: bool,
+    pub pass_through: bool,
+    /// Name of the conversion command that wrote the output, or of the last one tried.
+    pub backend: Option<String>,
+    /// Video extracted from a motion photo, to be converted by the video presets.
+    pub motion_photo_video: Option<PathBuf>,
+    /// Placeholder computed from the thumbnail of the file.
+    pub placeholder: Option<Placeholder>,
+    /// Difference hash of the image output, to find near-duplicate photos.
+    pub perceptual_hash: Option<u64>,
+    /// Secondary outputs written along the main one, see [`crate::converter::ExtraOutput`].
+    pub extra_outputs: Vec<PathBuf>,
     processing_started_at: PrimitiveDateTime,
     processing_finished_at: PrimitiveDateTime,
     metrics: Option<ProcessingMetrics>,
@@ -42,6 +67,13 @@ impl ProcessingResult {
             command: "".to_string(),
             command_log: "".to_string(),
             has_succeeded: false,
+            interrupted: false,
+            pass_through: false,
+            backend: None,
+            motion_photo_video: None,
+            placeholder: None,
+            perceptual_hash: None,
+            extra_outputs: vec![],
             processing_started_at: now(),
             processing_finished_at: now(),
             metrics: None,
@@ -63,6 +95,47 @@ impl ProcessingResult {
         self
     }
 
+    pub fn with_backend(mut self, backend: &str) -> ProcessingResult {
+        self.backend = Some(backend.to_owned());
+        self
+    }
+
+    pub fn with_motion_photo_video(mut self, path: PathBuf) -> ProcessingResult {
+        self.motion_photo_video = Some(path);
+        self
+    }
+
+    pub fn with_placeholder(mut self, placeholder: Placeholder) -> ProcessingResult {
+        self.placeholder = Some(placeholder);
+        self
+    }
+
+    pub fn with_perceptual_hash(mut self, hash: u64) -> ProcessingResult {
+        self.perceptual_hash = Some(hash);
+        self
+    }
+
+    pub fn with_extra_output(mut self, path: PathBuf) -> ProcessingResult {
+        self.extra_outputs.push(path);
+        self
+    }
+
+    pub fn with_processing_started_at(mut self, started_at: PrimitiveDateTime) -> ProcessingResult {
+        self.processing_started_at = started_at;
+        self
+    }
+
+    pub fn processing_seconds(&self) -> f64 {
+        (self.processing_finished_at - self.processing_started_at).as_seconds_f64()
+    }
+
+    pub fn fps(&self) -> Option<u32> {
+        match &self.metrics {
+            Some(ProcessingMetrics::Video(VideoMetrics { fps })) => Some(*fps),
+            _ => None,
+        }
+    }
+
     pub fn succeeded(mut self) -> ProcessingResult {
         self.has_succeeded = true;
         self.processing_finished_at = now();
@@ -74,52 +147,29 @@ impl ProcessingResult {
         self.processing_finished_at = now();
         self
     }
-}
 
-pub struct CommandRunner {
-    cwd: String,
-    cmd: String,
-}
-
-impl CommandRunner {
-    pub fn build(cwd: impl Into<String>) -> CommandRunner {
-        CommandRunner {
-            cwd: cwd.into(),
-            cmd: "".to_string(),
-        }
-            .with(r#"#!/bin/sh"#)
-            .with(r#"set -e"#)
+    /// The output is the source itself, copied or remuxed instead of transcoded.
+    pub fn passed_through(mut self) -> ProcessingResult {
+        self.pass_through = true;
+        self
     }
 
-    pub fn with(mut self, partial_cmd: impl AsRef<str>) -> CommandRunner {
-        self.cmd = self.cmd + partial_cmd.as_ref() + "\n";
-        self
+    /// The file was not processed to the end because of a shutdown, so the job must run again.
+    pub fn interrupted(mut self) -> ProcessingResult {
+        self.interrupted = true;
+        self.failed()
     }
+}
 
-    pub fn run(&self) -> ProcessingResult {
-        trace!("Will run command: {}", &self.cmd);
-        let result = ProcessingResult::new();
-        let capture_data_result = Exec::shell(&self.cmd)
-            .cwd(&self.cwd)
-            .stdout(Redirection::Pipe)
-            .stderr(Redirection::Merge)
-            .capture();
-        if let Err(e) = capture_data_result {
-            return result.with_command(self.cmd.clone()).with_command_log(e.to_string()).failed();
-        }
-        let capture_date = capture_data_result.unwrap();
-        let stdout = capture_date.stdout_str();
-        trace!("Result stdout: {stdout}");
-        let exit_status = capture_date.exit_status;
-        match exit_status {
-            ExitStatus::Exited(code) if code == 0 =>
-                result.with_command_log(stdout).succeeded(),
-            _ => result.with_command(self.cmd.clone()).with_command_log(stdout).failed(),
-        }
+pub fn remove_temporary_output(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!("Removed temporary output {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => error!("Failure removing temporary output {}: {e}", path.display()),
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub struct FileToBeProcessed<'a> {
     pub root: &'a str,
     pub output_folder: &'a str,
@@ -127,9 +177,42 @@ pub struct FileToBeProcessed<'a> {
     pub file: File,
     pub file_job: FileJob,
     pub exif: Exiftool,
+    pub shutdown: &'a Shutdown,
+    pub timeout: Duration,
+    /// Full paths of the sidecar files whose metadata is written into the outputs.
+    pub sidecars: Vec<String>,
 }
 
-impl FileToBeProcessed<'_> {
+impl<'a> FileToBeProcessed<'a> {
+    pub fn new(
+        ctx: &'a AppContext,
+        preset_name: &'a str,
+        file: File,
+        file_job: FileJob,
+        exif: Exiftool,
+        timeout: Duration,
+    ) -> FileToBeProcessed<'a> {
+        FileToBeProcessed {
+            root: &ctx.config.input_folder,
+            output_folder: &ctx.config.output_folder,
+            preset_name,
+            file: File {
+                mime_type: Some(exif.mime_type.clone()),
+                captured_at: exif.capture_date().or(file.captured_at),
+                ..file
+            },
+            file_job,
+            exif,
+            shutdown: &ctx.shutdown,
+            timeout,
+            sidecars: vec![],
+        }
+    }
+
+    pub fn with_sidecars(self, sidecars: Vec<String>) -> FileToBeProcessed<'a> {
+        FileToBeProcessed { sidecars, ..self }
+    }
+
     pub fn file_full_path(&self) -> &str {
         &self.file.file_full_path
     }
@@ -150,36 +233,82 @@ impl FileToBeProcessed<'_> {
         self.exif.mime_type.contains("video")
     }
 
-    pub fn relative_path_with_file_stem_and_a_given_extension(
+    pub fn is_audio(&self) -> bool {
+        self.exif.mime_type.starts_with("audio/")
+    }
+
+    /// Folder of the outputs of this file for the preset, mirroring its folder in the input.
+    pub fn output_folder_path(&self) -> PathBuf {
+        Path::new(self.output_folder).join(self.relative_path())
+    }
+
+    pub fn output_path(&self, target_extension: &str) -> PathBuf {
+        self.output_folder_path()
+            .join(format!("{}.{}", self.file_stem(), target_extension))
+    }
+
+    /// Conversions write to a hidden file next to the final output, keeping the extension so tools
+    /// still pick the right format. It is only renamed into place once everything succeeded.
+    pub fn temporary_output_path(&self, target_extension: &str) -> PathBuf {
+        self.output_folder_path()
+            .join(format!(".{}.fixmylib-tmp.{}", self.file_stem(), target_extension))
+    }
+
+    /// Renames the temporary output of a successful conversion to its final path, or removes it
+    /// when the conversion failed or was interrupted.
+    pub fn finalize_output(
         &self,
         target_extension: &str,
-    ) -> String {
-        format!(
-            "{}/{}.{}",
-            self.relative_path(),
-            self.file_stem(),
-            target_extension
-        )
+        result: ProcessingResult,
+    ) -> ProcessingResult {
+        let temporary_path = self.temporary_output_path(target_extension);
+        if result.has_succeeded {
+            let output_path = self.output_path(target_extension);
+            if let Err(e) = std::fs::rename(&temporary_path, &output_path) {
+                let command_log = format!(
+                    "{}\nFailure moving {} to {}: {e}",
+                    result.command_log,
+                    temporary_path.display(),
+                    output_path.display()
+                );
+                remove_temporary_output(&temporary_path);
+                return result.with_command_log(command_log).failed();
+            }
+            // Renaming a hard link over another link to the same file does nothing, which leaves
+            // the temporary output behind when a pass-through links the unchanged source again.
+            remove_temporary_output(&temporary_path);
+            return result;
+        }
+        remove_temporary_output(&temporary_path);
+        result
     }
-    pub fn relative_path(&self) -> String {
-        format!(
-            "{}/{}",
-            self.preset_name,
-            self.folder_full_path().replacen(self.root, ".", 1)
-        )
+
+    pub fn relative_path(&self) -> PathBuf {
+        Path::new(self.preset_name).join(self.relative_folder_path())
+    }
+
+    /// Folder of the file relative to the input folder.
+    pub fn relative_folder_path(&self) -> &Path {
+        let folder_full_path = Path::new(self.folder_full_path());
+        folder_full_path
+            .strip_prefix(self.root)
+            .or_else(|_| folder_full_path.strip_prefix("/"))
+            .unwrap_or(folder_full_path)
     }
 }
 
 pub async fn run(ctx: &AppContext) -> Result<()> {
+    for preset_name in get_preset_names(ctx) {
+        refresh_file_job_priorities(ctx, &preset_name).await?;
+    }
     loop {
         info!("Checking for unprocessed files...");
         let mut total_processed_files_count = 0;
         for preset_name in get_preset_names(ctx) {
-            debug!("Creating jobs for preset {preset_name}");
+            if ctx.shutdown.is_requested() {
+                break;
+            }
             let ticker = Ticker::new();
-            create_file_jobs_for_unprocessed_files(ctx, &preset_name)
-                .await
-                .expect("it should work flawless to create file jobs");
             let processed_files_count = Processor::new(ctx)
                 .process_pending_file_jobs(&preset_name)
                 .await
@@ -197,14 +326,18 @@ pub async fn run(ctx: &AppContext) -> Result<()> {
             save_failed_jobs_report(ctx).await?;
         }
 
-        sleep(Duration::from_secs(
-            ctx.config.seconds_between_processor_runs,
-        ))
-            .await;
+        if ctx.shutdown.is_requested() {
+            info!("Processor stopped.");
+            return Ok(());
+        }
+        tokio::select! {
+            _ = sleep(Duration::from_secs(ctx.config.seconds_between_processor_runs)) => {},
+            _ = ctx.shutdown.requested() => {},
+        }
     }
 }
 
-fn get_preset_names(ctx: &AppContext) -> Vec<String> {
+pub fn get_preset_names(ctx: &AppContext) -> Vec<String> {
     let mut presets = vec![];
     if ctx.config.enable_preview_preset {
         presets.push("preview".into());
@@ -212,20 +345,50 @@ fn get_preset_names(ctx: &AppContext) -> Vec<String> {
     if ctx.config.enable_thumbnail_preset {
         presets.push("thumbnail".into());
     }
+    if ctx.config.enable_original_preset {
+        presets.push(ORIGINAL_PRESET_NAME.into());
+    }
+    if ctx.config.enable_live_preset {
+        presets.push(LIVE_PRESET_NAME.into());
+    }
     presets
 }
 
+/// The original preset mirrors every input file, but not the videos extracted from motion photos.
+/// The live preset only converts the clips of Live Photos, which the other presets skip. Sidecars
+/// are never converted.
+fn preset_files(preset_name: &str) -> PresetFiles {
+    match preset_name {
+        ORIGINAL_PRESET_NAME => PresetFiles {
+            live_photo_clips: None,
+            motion_photo_videos: false,
+            sidecars: true,
+        },
+        _ => PresetFiles {
+            live_photo_clips: Some(preset_name == LIVE_PRESET_NAME),
+            motion_photo_videos: true,
+            sidecars: false,
+        },
+    }
+}
+
 pub async fn create_file_jobs_for_unprocessed_files(
     ctx: &AppContext,
     preset_name: &str,
 ) -> Result<()> {
-    let mut offset = 0;
+    let prioritizer = JobPrioritizer::new(ctx);
+    let mut failed_count = 0;
     let limit = 100;
     let mut count = 0;
     loop {
-        let files =
-            db::get_unprocessed_files_for_a_given_preset_name(&ctx.db, preset_name, offset, limit)
-                .await?;
+        let files = db::get_files_without_file_job_for_a_given_preset_name(
+            &ctx.db,
+            preset_name,
+            &preset_files(preset_name),
+            failed_count,
+            limit,
+        )
+            .await?;
         if files.is_empty() {
             break;
         }
@@ -242,6 +405,7 @@ pub async fn create_file_jobs_for_unprocessed_files(
         let jobs: Vec<FileJob> = files
             .into_iter()
             .map(|f| FileJob {
+                priority: prioritizer.priority(&f),
                 file_full_path: f.file_full_path,
                 preset_name: preset_name.to_owned(),
                 created_at: now(),
@@ -249,18 +413,24 @@ pub async fn create_file_jobs_for_unprocessed_files(
                 command: None,
                 command_log: None,
                 has_succeeded: None,
+                attempts: 0,
+                next_attempt_at: None,
+                processing_seconds: None,
+                megapixels: None,
+                fps: None,
+                pass_through: false,
+                backend: None,
             })
             .collect();
         for job in jobs {
             match db::upsert_file_job(&ctx.db, job).await {
                 Ok(_) => count += 1,
                 Err(e) => {
-                    error!("Failure inserting file_job: {e}")
+                    error!("Failure inserting file_job: {e}");
+                    failed_count += 1;
                 }
             }
         }
-
-        offset += limit;
     }
     if count != 0 {
         info!("Created {count} jobs to process files.");
@@ -268,27 +438,136 @@ pub async fn create_file_jobs_for_unprocessed_files(
     Ok(())
 }
 
+/// Recomputes the priority of unfinished jobs, so changes to pinned folders or media type
+/// priority also apply to jobs created before them.
+async fn refresh_file_job_priorities(ctx: &AppContext, preset_name: &str) -> Result<()> {
+    let prioritizer = JobPrioritizer::new(ctx);
+    let mut offset = 0;
+    let limit = 1000;
+    loop {
+        let files = db::get_files_with_unfinished_file_job_for_a_given_preset_name(
+            &ctx.db,
+            preset_name,
+            offset,
+            limit,
+        )
+            .await?;
+        if files.is_empty() {
+            break;
+        }
+        let file_priorities: Vec<(String, i64)> = files
+            .iter()
+            .map(|f| (f.file_full_path.clone(), prioritizer.priority(f)))
+            .collect();
+        db::update_file_job_priorities(&ctx.db, preset_name, &file_priorities).await?;
+        offset += limit;
+    }
+    Ok(())
+}
+
+/// Jobs are created before exiftool runs on their file, so the jobs of the other presets are
+/// ranked again once its capture date is known.
+async fn reprioritize_file_jobs(ctx: &AppContext, files: &[File]) -> Result<()> {
+    let prioritizer = JobPrioritizer::new(ctx);
+    let file_priorities: Vec<(String, i64)> = files
+        .iter()
+        .filter(|f| f.captured_at.is_some())
+        .map(|f| (f.file_full_path.clone(), prioritizer.priority(f)))
+        .collect();
+    if file_priorities.is_empty() {
+        return Ok(());
+    }
+    for preset_name in get_preset_names(ctx) {
+        db::update_file_job_priorities(&ctx.db, &preset_name, &file_priorities).await?;
+    }
+    Ok(())
+}
+
+/// Longest delay between two attempts, whatever the configured delay and the attempts made.
+const MAX_BACKOFF_SECONDS: u64 = 365 * 24 * 3600;
+
+pub struct RetryPolicy {
+    max_attempts: i32,
+    seconds_between_retries: u64,
+}
+
+impl RetryPolicy {
+    pub fn new(ctx: &AppContext) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: ctx.config.max_job_attempts.max(1),
+            seconds_between_retries: ctx.config.seconds_between_job_retries,
+        }
+    }
+
+    /// Updates a file job with the outcome of one processing attempt. Failed jobs stay pending,
+    /// with an exponentially growing delay, until the attempts budget runs out. Interrupted jobs
+    /// stay pending without using an attempt.
+    pub fn apply(&self, file_job: FileJob, result: &ProcessingResult) -> FileJob {
+        if result.interrupted {
+            return FileJob {
+                finished_at: None,
+                has_succeeded: None,
+                next_attempt_at: None,
+                ..file_job
+            };
+        }
+        let attempts = file_job.attempts.saturating_add(1);
+        if result.has_succeeded || attempts >= self.max_attempts {
+            return FileJob {
+                finished_at: Some(now()),
+                has_succeeded: Some(result.has_succeeded),
+                attempts,
+                next_attempt_at: None,
+                ..file_job
+            };
+        }
+        let next_attempt_at = now() + self.backoff(attempts);
+        info!(
+            "Attempt {attempts} of {} failed for {} on preset {}, retrying after {next_attempt_at}",
+            self.max_attempts, file_job.file_full_path, file_job.preset_name
+        );
+        FileJob {
+            finished_at: None,
+            has_succeeded: None,
+            attempts,
+            next_attempt_at: Some(next_attempt_at),
+            ..file_job
+        }
+    }
+
+    fn backoff(&self, attempts: i32) -> Duration {
+        let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+        let seconds = self.seconds_between_retries.saturating_mul(1 << exponent);
+        Duration::from_secs(seconds.min(MAX_BACKOFF_SECONDS))
+    }
+}
+
 struct Processor<'a> {
-    image_converter: ImageConverterProcessor,
-    video_converter: VideoConverterProcessor,
+    converters: ConverterRegistry,
+    retry_policy: RetryPolicy,
+    timeout_policy: TimeoutPolicy,
     ctx: &'a AppContext,
 }
 
 impl Processor<'_> {
-    fn new(ctx: &AppContext) -> Processor {
+    fn new(ctx: &AppContext) -> Processor<'_> {
         Processor {
-            image_converter: ImageConverterProcessor::new(ctx),
-            video_converter: VideoConverterProcessor::new(ctx),
+            converters: ConverterRegistry::new(ctx),
+            retry_policy: RetryPolicy::new(ctx),
+            timeout_policy: TimeoutPolicy::new(ctx),
             ctx,
         }
     }
     pub async fn process_pending_file_jobs(&self, preset_name: &str) -> Result<i32> {
-        let mut offset = 0;
         let limit = 100;
         let mut count = 0;
-        loop {
+        while !self.ctx.shutdown.is_requested() {
+            // Jobs are created and fetched again before each batch, so files discovered in the
+            // meantime are picked up according to their priority. Processed jobs are either
+            // finished or scheduled for a later attempt, so they leave the pending set.
+            create_file_jobs_for_unprocessed_files(self.ctx, preset_name).await?;
             let files_and_jobs =
-                db::get_unprocessed_file_and_jobs(&self.ctx.db, preset_name, offset, limit).await?;
+                db::get_unprocessed_file_and_jobs(&self.ctx.db, preset_name, 0, limit).await?;
 
             if files_and_jobs.is_empty() {
                 break;
@@ -298,35 +577,54 @@ impl Processor<'_> {
             for (file, _) in files_and_jobs.iter() {
                 debug!("{}", file.file_full_path)
             }
+            let files: Vec<File> = files_and_jobs.iter().map(|(file, _)| file.clone()).collect();
+            let mut sidecars = db::get_sidecars_of_files(&self.ctx.db, &files).await?;
 
-            let processed_data = self.process_files(files_and_jobs, preset_name);
+            // Conversions block for a long time, so the worker is handed over to the other tasks,
+            // e.g. the shutdown signal listener, meanwhile.
+            let processed_data =
+                tokio::task::block_in_place(|| {
+                    self.process_files(files_and_jobs, &mut sidecars, preset_name)
+                });
 
             print_statistics(&processed_data);
 
+            let processed_files: Vec<File> =
+                processed_data.iter().map(|(file, _, _)| file.clone()).collect();
+            db::update_files_metadata(&self.ctx.db, &processed_files).await?;
+            reprioritize_file_jobs(self.ctx, &processed_files).await?;
+            register_motion_photo_videos(self.ctx, &processed_data).await?;
+            save_placeholders(self.ctx, &processed_data).await?;
+            save_perceptual_hashes(self.ctx, &processed_data).await?;
+
             let updated_file_jobs = processed_data
                 .into_iter()
-                .map(
-                    |(
-                         file,
-                         file_job,
-                         ProcessingResult {
-                             command,
-                             command_log,
-                             has_succeeded,
-                             ..
-                         },
-                     )| {
-                        FileJob {
-                            file_full_path: file.file_full_path,
-                            preset_name: preset_name.to_owned(),
-                            finished_at: Some(now()),
-                            command: Some(command),
-                            command_log: Some(command_log),
-                            has_succeeded: Some(has_succeeded),
-                            ..file_job
-                        }
-                    }
-                )
+                .map(|(file, file_job, result)| {
+                    let file_job = FileJob {
+                        file_full_path: file.file_full_path,
+                        preset_name: preset_name.to_owned(),
+                        command: Some(result.command.clone()),
+                        command_log: Some(result.command_log.clone()),
+                        processing_seconds: result
+                            .has_succeeded
+                            .then(|| result.processing_seconds()),
+                        megapixels: match &result.metrics {
+                            Some(ProcessingMetrics::Image(ImageMetrics { megapixels })) => {
+                                Some(*megapixels)
+                            }
+                            _ => None,
+                        },
+                        fps: result.fps().and_then(|fps| i32::try_from(fps).ok()),
+                        pass_through: result.pass_through,
+                        backend: result.backend.clone(),
+                        ..file_job
+                    };
+                    let file_job = self.retry_policy.apply(file_job, &result);
+                    self.ctx
+                        .metrics
+                        .record_file_job(preset_name, file_job_status(&file_job));
+                    file_job
+                })
                 .collect::<Vec<FileJob>>();
 
 
@@ -335,8 +633,6 @@ impl Processor<'_> {
             db::upsert_file_jobs(&self.ctx.db, updated_file_jobs).await?;
             tick.elapsed("To insert a batch of filejobs");
             count += job_count;
-
-            offset += limit;
         }
         if count != 0 {
             info!("Processed {count} files.");
@@ -347,6 +643,7 @@ impl Processor<'_> {
     fn process_files(
         &self,
         files: Vec<(File, FileJob)>,
+        sidecars: &mut HashMap<String, Vec<String>>,
         preset_name: &str,
     ) -> Vec<(File, FileJob, ProcessingResult)> {
         enum ExifProcessing {
@@ -356,13 +653,18 @@ impl Processor<'_> {
         let files_count = files.len();
         let exifs: Vec<ExifProcessing> = files
             .into_iter()
-            .map(|(file, job)| match exiftool_on_file(&file.file_full_path) {
-                Ok(exif) => ExifProcessing::Success((file, job, exif)),
-                Err(e) => ExifProcessing::Failure((
-                    file,
-                    job,
-                    ProcessingResult::new().with_command_log(format!("Failure extracting exif data: {:?}", e)).failed(),
-                )),
+            .map(|(file, job)| {
+                let started_at = Instant::now();
+                let exif = exiftool_on_file(&file.file_full_path);
+                self.ctx.metrics.record_exiftool(started_at.elapsed());
+                match exif {
+                    Ok(exif) => ExifProcessing::Success((file, job, exif)),
+                    Err(e) => ExifProcessing::Failure((
+                        file,
+                        job,
+                        ProcessingResult::new().with_command_log(format!("Failure extracting exif data: {e}")).failed(),
+                    )),
+                }
             })
             .collect();
         let (success_exifs, failed_exifs): (Vec<_>, Vec<_>) = exifs
@@ -374,61 +676,27 @@ impl Processor<'_> {
                 failed_exifs.len()
             );
         }
-        let (media_files, non_media_files): (Vec<_>, Vec<_>) = success_exifs
+        let files_to_be_processed: Vec<_> = success_exifs
             .into_iter()
             .flat_map(|e| match e {
                 ExifProcessing::Success(file_and_exif) => Some(file_and_exif),
                 ExifProcessing::Failure(_) => None,
             })
-            .map(|(file, file_job, exif)| FileToBeProcessed {
-                root: &self.ctx.config.input_folder,
-                output_folder: &self.ctx.config.output_folder,
-                preset_name,
-                file,
-                file_job,
-                exif,
+            .map(|(file, file_job, exif)| {
+                let timeout = self.timeout_policy.timeout(preset_name, &exif);
+                let file_sidecars = sidecars.remove(&file.file_full_path).unwrap_or_default();
+                FileToBeProcessed::new(self.ctx, preset_name, file, file_job, exif, timeout)
+                    .with_sidecars(file_sidecars)
             })
-            .partition(|f| f.is_video() || f.is_image());
-
-        let (image_files, video_files): (Vec<_>, Vec<_>) =
-            media_files.into_iter().partition(|f| f.is_image());
-
-        info!(
-            "Found {} images, {} videos and {} non media files out of {} files.",
-            image_files.len(),
-            video_files.len(),
-            non_media_files.len(),
-            files_count
-        );
-        info!("Processing images...");
-        let images_processed: Vec<_> = image_files
-            .clone()
-            .into_iter()
-            .zip(self.image_converter.convert_files(image_files))
-            .map(|(f, r)| (f.file, f.file_job, r))
             .collect();
-        info!("Processing videos...");
-        let videos_processed: Vec<_> = video_files
-            .clone()
+        debug!("Converting {} out of {} files.", files_to_be_processed.len(), files_count);
+        let files_processed: Vec<_> = self
+            .converters
+            .convert_files(files_to_be_processed)
             .into_iter()
-            .zip(self.video_converter.convert_files(video_files))
             .map(|(f, r)| (f.file, f.file_job, r))
             .collect();
 
-        let non_media_files_processed: Vec<_> = non_media_files
-            .into_iter()
-            .map(|f| {
-                (
-                    f.file,
-                    f.file_job,
-                    ProcessingResult::new().with_command_log(format!(
-                        "File is neither imager or video. Mime type: {}",
-                        f.exif.mime_type
-                    )).succeeded(),
-                )
-            })
-            .collect();
-
         let failed_exifs_processed: Vec<_> = failed_exifs
             .into_iter()
             .flat_map(|e| match e {
@@ -438,15 +706,79 @@ impl Processor<'_> {
             .collect();
 
         [
-            images_processed,
-            videos_processed,
-            non_media_files_processed,
+            files_processed,
             failed_exifs_processed,
         ]
             .concat()
     }
 }
 
+/// Adds the videos extracted from motion photos to the files, linked to their photo, so the video
+/// presets convert them on the next batches.
+async fn register_motion_photo_videos(
+    ctx: &AppContext,
+    data: &[(File, FileJob, ProcessingResult)],
+) -> Result<()> {
+    for (photo, _, result) in data {
+        let Some(video_path) = &result.motion_photo_video else {
+            continue;
+        };
+        let video = match motion_photo_video_file(photo, video_path) {
+            Ok(video) => video,
+            Err(e) => {
+                error!("Failure reading the video extracted to {}: {e}", video_path.display());
+                continue;
+            }
+        };
+        let motion_photo = MotionPhoto {
+            photo_full_path: photo.file_full_path.clone(),
+            video_full_path: video.file_full_path.clone(),
+            created_at: now(),
+        };
+        db::upsert_file(&ctx.db, video).await?;
+        db::insert_motion_photo(&ctx.db, &motion_photo).await?;
+    }
+    Ok(())
+}
+
+async fn save_placeholders(
+    ctx: &AppContext,
+    data: &[(File, FileJob, ProcessingResult)],
+) -> Result<()> {
+    for (file, _, result) in data {
+        let Some(placeholder) = &result.placeholder else {
+            continue;
+        };
+        let placeholder = FilePlaceholder {
+            file_full_path: file.file_full_path.clone(),
+            blurhash: placeholder.blurhash.clone(),
+            width: i32::try_from(placeholder.width).unwrap_or(i32::MAX),
+            height: i32::try_from(placeholder.height).unwrap_or(i32::MAX),
+            created_at: now(),
+        };
+        db::upsert_placeholder(&ctx.db, &placeholder).await?;
+    }
+    Ok(())
+}
+
+async fn save_perceptual_hashes(
+    ctx: &AppContext,
+    data: &[(File, FileJob, ProcessingResult)],
+) -> Result<()> {
+    for (file, _, result) in data {
+        let Some(hash) = result.perceptual_hash else {
+            continue;
+        };
+        let perceptual_hash = FilePerceptualHash {
+            file_full_path: file.file_full_path.clone(),
+            dhash: hash as i64,
+            created_at: now(),
+        };
+        db::upsert_perceptual_hash(&ctx.db, &perceptual_hash).await?;
+    }
+    Ok(())
+}
+
 fn print_statistics(data: &[(File, FileJob, ProcessingResult)]) {
     let elements = data.iter()
         .flat_map(|(_, _, ProcessingResult { metrics, .. })|
@@ -456,6 +788,9 @@ fn print_statistics(data: &[(File, FileJob, ProcessingResult)]) {
                 None
             }
         ).collect::<Vec<u32>>();
+    if elements.is_empty() {
+        return;
+    }
 
     let sum: u32 = elements.iter().sum();
     let mean = sum as f64 / elements.len() as f64;
@@ -490,4 +825,88 @@ async fn save_failed_jobs_report(ctx: &AppContext) -> Result<()> {
     writer.flush()?;
     info!("Saved report at {report_path} for {} failed conversions.",failed_count);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_policy(max_attempts: i32, seconds_between_retries: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            seconds_between_retries,
+        }
+    }
+
+    fn file_job(attempts: i32) -> FileJob {
+        FileJob {
+            file_full_path: "/in/a.jpg".to_owned(),
+            preset_name: "preview".to_owned(),
+            created_at: now(),
+            finished_at: None,
+            command: None,
+            command_log: None,
+            has_succeeded: None,
+            attempts,
+            next_attempt_at: None,
+            priority: 0,
+            processing_seconds: None,
+            megapixels: None,
+            fps: None,
+            pass_through: false,
+            backend: None,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_after_each_attempt() {
+        let policy = retry_policy(5, 60);
+        assert_eq!(policy.backoff(1), Duration::from_secs(60));
+        assert_eq!(policy.backoff(2), Duration::from_secs(120));
+        assert_eq!(policy.backoff(3), Duration::from_secs(240));
+    }
+
+    #[test]
+    fn backoff_stops_growing() {
+        let policy = retry_policy(100, 60);
+        assert_eq!(policy.backoff(17), Duration::from_secs(60 << 16));
+        assert_eq!(policy.backoff(i32::MAX), Duration::from_secs(60 << 16));
+        assert_eq!(policy.backoff(i32::MIN), Duration::from_secs(60));
+        let policy = retry_policy(100, u64::MAX);
+        assert_eq!(policy.backoff(i32::MAX), Duration::from_secs(MAX_BACKOFF_SECONDS));
+    }
+
+    #[test]
+    fn failed_jobs_are_retried_until_the_attempts_run_out() {
+        let policy = retry_policy(3, 60);
+        let failed = ProcessingResult::new().failed();
+
+        let retried = policy.apply(file_job(1), &failed);
+        assert_eq!(retried.attempts, 2);
+        assert_eq!(retried.finished_at, None);
+        assert!(retried.next_attempt_at.is_some());
+
+        let finished = policy.apply(file_job(2), &failed);
+        assert_eq!(finished.attempts, 3);
+        assert!(finished.finished_at.is_some());
+        assert_eq!(finished.has_succeeded, Some(false));
+        assert_eq!(finished.next_attempt_at, None);
+    }
+
+    #[test]
+    fn attempts_do_not_overflow() {
+        let policy = retry_policy(i32::MAX, u64::MAX);
+        let job = policy.apply(file_job(i32::MAX), &ProcessingResult::new().failed());
+        assert_eq!(job.attempts, i32::MAX);
+        assert_eq!(job.has_succeeded, Some(false));
+    }
+
+    #[test]
+    fn interrupted_jobs_keep_their_attempts() {
+        let policy = retry_policy(3, 60);
+        let job = policy.apply(file_job(2), &ProcessingResult::new().interrupted());
+        assert_eq!(job.attempts, 2);
+        assert_eq!(job.finished_at, None);
+        assert_eq!(job.next_attempt_at, None);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::processor::remove_temporary_output;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Tags where cameras store JPEG previews in RAW files. The largest one is usually full size.
+pub const PREVIEW_TAGS: [&str; 3] = ["JpgFromRaw", "PreviewImage", "OtherImage"];
+
+/// File the preview stored in `tag` is extracted to, next to the output `to`.
+pub fn preview_path(to: &Path, tag: &str) -> PathBuf {
+    let mut path = to.as_os_str().to_owned();
+    path.push(format!(".{tag}"));
+    path.into()
+}
+
+/// Renames the largest of the JPEG previews extracted to `previews` to `to`, removes the others
+/// and returns its dimensions. Fails when there is none, or when its smallest side is under
+/// `min_size` pixels.
+pub fn keep_largest_preview(
+    previews: &[PathBuf],
+    to: &Path,
+    min_size: u32,
+) -> io::Result<(u32, u32)> {
+    let mut largest: Option<(&PathBuf, (u32, u32))> = None;
+    for preview in previews {
+        let Some((width, height)) = std::fs::read(preview)
+            .ok()
+            .and_then(|jpeg| jpeg_dimensions(&jpeg))
+        else {
+            continue;
+        };
+        let is_larger = match &largest {
+            Some((_, (w, h))) => width * height > w * h,
+            None => true,
+        };
+        if is_larger {
+            largest = Some((preview, (width, height)));
+        }
+    }
+    let result = match largest {
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no embedded JPEG preview was found",
+        )),
+        Some((_, (width, height))) if width.min(height) < min_size => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the largest embedded preview is {width}x{height}, smaller than {min_size}px"),
+        )),
+        Some((preview, dimensions)) => std::fs::rename(preview, to).map(|_| dimensions),
+    };
+    for preview in previews {
+        remove_temporary_output(preview);
+    }
+    result
+}
+
+/// Reads the dimensions in the start of frame segment of a JPEG.
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u32, u32)> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut position = 2;
+    while position + 4 <= jpeg.len() {
+        if jpeg[position] != 0xFF {
+            return None;
+        }
+        let marker = jpeg[position + 1];
+        // Markers may be preceded by any number of fill bytes.
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        // SOF0 to SOF15, except DHT, JPG and DAC which share the range.
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let frame = jpeg.get(position + 5..position + 9)?;
+            let height = u16::from_be_bytes([frame[0], frame[1]]);
+            let width = u16::from_be_bytes([frame[2], frame[3]]);
+            return Some((width.into(), height.into()));
+        }
+        let length = u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]);
+        position += 2 + length as usize;
+    }
+    None
+}
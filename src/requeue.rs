@@ -0,0 +1,225 @@
+use crate::db::{self, FileJob, FileJobSelection};
+use crate::errors::FixMyLibErrors;
+use crate::AppContext;
+use anyhow::Result;
+
+/// MIME types exiftool gives to common media extensions. Files only get their MIME type once
+/// processed, so the ones that failed before it was stored are matched by extension instead.
+const EXTENSION_MIME_TYPES: [(&str, &str); 38] = [
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("bmp", "image/bmp"),
+    ("avif", "image/avif"),
+    ("dng", "image/x-adobe-dng"),
+    ("cr2", "image/x-canon-cr2"),
+    ("cr3", "image/x-canon-cr3"),
+    ("nef", "image/x-nikon-nef"),
+    ("arw", "image/x-sony-arw"),
+    ("orf", "image/x-olympus-orf"),
+    ("rw2", "image/x-panasonic-rw2"),
+    ("raf", "image/x-fujifilm-raf"),
+    ("mov", "video/quicktime"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/x-m4v"),
+    ("avi", "video/x-msvideo"),
+    ("mkv", "video/x-matroska"),
+    ("3gp", "video/3gpp"),
+    ("mts", "video/m2ts"),
+    ("m2ts", "video/m2ts"),
+    ("wmv", "video/x-ms-wmv"),
+    ("mpg", "video/mpeg"),
+    ("webm", "video/webm"),
+    ("m4a", "audio/mp4"),
+    ("mp3", "audio/mpeg"),
+    ("aac", "audio/aac"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/x-wav"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("aiff", "audio/x-aiff"),
+];
+
+#[derive(clap::Args, serde::Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct RequeueArgs {
+    /// Only requeue jobs of this preset.
+    #[arg(long)]
+    pub preset: Option<String>,
+
+    /// Only requeue jobs that have failed after exhausting all their attempts.
+    #[arg(long)]
+    pub failed: bool,
+
+    /// Only requeue jobs whose file path matches this glob, e.g. "/media-in/2023/**/*.mov".
+    #[arg(long)]
+    pub path_glob: Option<String>,
+
+    /// Only requeue jobs whose file MIME type matches this glob, e.g. "video/*".
+    #[arg(long)]
+    pub mime_type: Option<String>,
+
+    /// Only requeue jobs whose command log matches this regular expression (case insensitive).
+    #[arg(long)]
+    pub error_pattern: Option<String>,
+
+    /// List the jobs that would be requeued without changing them.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl RequeueArgs {
+    fn selection(&self) -> Result<FileJobSelection, FixMyLibErrors> {
+        if self.preset.is_none()
+            && !self.failed
+            && self.path_glob.is_none()
+            && self.mime_type.is_none()
+            && self.error_pattern.is_none()
+        {
+            return Err(FixMyLibErrors::InvalidRequest(
+                "at least one of preset, failed, path glob, MIME type or error pattern must be given to requeue jobs".to_owned(),
+            ));
+        }
+        let mime_type_regex = self.mime_type.as_deref().map(glob_to_regex);
+        let mime_type_extensions = match &mime_type_regex {
+            Some(regex) => extensions_of_mime_types(regex)?,
+            None => vec![],
+        };
+        Ok(FileJobSelection {
+            preset_name: self.preset.clone(),
+            only_failed: self.failed,
+            path_regex: self.path_glob.as_deref().map(glob_to_regex),
+            mime_type_regex,
+            mime_type_extensions,
+            error_regex: self.error_pattern.clone(),
+        })
+    }
+}
+
+pub async fn requeue_file_jobs(ctx: &AppContext, args: &RequeueArgs) -> Result<Vec<FileJob>> {
+    let selection = args.selection()?;
+    // The pattern is matched by Postgres, so it is compiled by Postgres too.
+    if let Some(error_pattern) = &selection.error_regex {
+        if let Some(e) = db::check_regex(&ctx.db, error_pattern).await? {
+            return Err(FixMyLibErrors::InvalidRequest(format!("invalid error pattern: {e}")).into());
+        }
+    }
+    let file_jobs = db::get_file_jobs_by_selection(&ctx.db, &selection).await?;
+    if !args.dry_run && !file_jobs.is_empty() {
+        db::reset_file_jobs(&ctx.db, &file_jobs).await?;
+        info!("Requeued {} file jobs.", file_jobs.len());
+    }
+    Ok(file_jobs)
+}
+
+pub async fn run(ctx: &AppContext, args: &RequeueArgs) -> Result<()> {
+    let file_jobs = requeue_file_jobs(ctx, args).await?;
+    for job in file_jobs.iter() {
+        println!(
+            "{}\t{}\t{}",
+            job.preset_name,
+            file_job_status(job),
+            job.file_full_path
+        );
+    }
+    if args.dry_run {
+        println!("{} file jobs would be requeued.", file_jobs.len());
+    } else {
+        println!("{} file jobs requeued.", file_jobs.len());
+    }
+    Ok(())
+}
+
+pub fn file_job_status(job: &FileJob) -> &'static str {
+    match (job.finished_at, job.has_succeeded) {
+        (Some(_), Some(true)) => "succeeded",
+        (Some(_), _) => "failed",
+        (None, _) if job.next_attempt_at.is_some() => "retrying",
+        (None, _) => "pending",
+    }
+}
+
+/// Extensions whose MIME type matches the regular expression.
+fn extensions_of_mime_types(mime_type_regex: &str) -> Result<Vec<String>, FixMyLibErrors> {
+    let regex = regex::Regex::new(mime_type_regex)
+        .map_err(|e| FixMyLibErrors::InvalidRequest(format!("invalid MIME type: {e}")))?;
+    Ok(EXTENSION_MIME_TYPES
+        .iter()
+        .filter(|(_, mime_type)| regex.is_match(mime_type))
+        .map(|(extension, _)| extension.to_string())
+        .collect())
+}
+
+/// Translates a shell-like glob into an anchored POSIX regular expression: `**` matches across
+/// folders, `**/` also matching no folder at all, `*` and `?` stop at `/`.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The translations are matched by Postgres, so they are compared as text: they only use
+    // anchors, `.`, `*`, `?`, groups, bracket expressions and backslashes before punctuation,
+    // which read the same as advanced regular expressions of Postgres.
+
+    #[test]
+    fn regex_metacharacters_are_escaped() {
+        assert_eq!(
+            glob_to_regex("/in/(2023) [best]+{1,2}|a^b$c.d\\e.jpg"),
+            r"^/in/\(2023\) \[best\]\+\{1,2\}\|a\^b\$c\.d\\e\.jpg$"
+        );
+        assert_eq!(glob_to_regex("/in/#&~-.jpg"), r"^/in/\#\&\~\-\.jpg$");
+    }
+
+    #[test]
+    fn star_and_question_mark_stay_in_a_folder() {
+        assert_eq!(glob_to_regex("/in/*.mov"), r"^/in/[^/]*\.mov$");
+        assert_eq!(glob_to_regex("/in/clip?.mov"), r"^/in/clip[^/]\.mov$");
+        assert_eq!(glob_to_regex("video/*"), r"^video/[^/]*$");
+    }
+
+    #[test]
+    fn double_star_crosses_folders() {
+        assert_eq!(glob_to_regex("/in/**/*.mov"), r"^/in/(.*/)?[^/]*\.mov$");
+        assert_eq!(glob_to_regex("/in/**.mov"), r"^/in/.*\.mov$");
+        assert_eq!(glob_to_regex("/in/**"), r"^/in/.*$");
+    }
+
+    #[test]
+    fn mime_type_globs_select_extensions() {
+        let extensions = extensions_of_mime_types(&glob_to_regex("audio/*")).unwrap();
+        assert!(extensions.contains(&"m4a".to_owned()));
+        assert!(!extensions.contains(&"mov".to_owned()));
+        assert_eq!(
+            extensions_of_mime_types(&glob_to_regex("image/jpeg")).unwrap(),
+            vec!["jpg", "jpeg"]
+        );
+    }
+}
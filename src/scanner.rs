@@ -4,12 +4,14 @@ use crate::db::{
 };
 use crate::errors::FixMyLibErrors;
 use crate::errors::FixMyLibErrors::PathParsing;
+use crate::live_photo::pair_live_photos;
+use crate::sidecar::associate_sidecars;
 use crate::{time, AppContext};
 use anyhow::{Context, Result};
 use rayon::iter::ParallelIterator;
 use rayon::prelude::ParallelBridge;
 use sqlx::types::time::{OffsetDateTime, PrimitiveDateTime};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 use tokio::time::sleep;
 use uuid::Uuid;
@@ -17,39 +19,54 @@ use walkdir::{DirEntry, WalkDir};
 
 pub async fn run(ctx: &AppContext) -> anyhow::Result<()> {
     loop {
-        let mut unfinished_scan_jobs = get_unfinished_filescan_jobs(&ctx.db).await?;
-        if unfinished_scan_jobs.is_empty() {
-            info!(
-                "Starting new full scan for files on {}",
-                ctx.config.input_folder.clone()
-            );
-            let job = FilescanJob {
-                id: Uuid::new_v4(),
-                full_path: ctx.config.input_folder.clone(),
-                created_at: time::now(),
-                finished_at: None,
-            };
-            upsert_filescan_job(&ctx.db, job.clone()).await?;
-            unfinished_scan_jobs.push(job);
-        }
-        for job in unfinished_scan_jobs {
-            run_job_for_folders(ctx, &job).await?;
-            run_job_for_files(ctx, job.clone()).await?;
-            upsert_filescan_job(
-                &ctx.db,
-                FilescanJob {
-                    finished_at: Some(time::now()),
-                    ..job
-                },
-            )
-            .await?;
-        }
-        debug!("Done scanning all filescanjobs");
+        scan(ctx).await?;
         sleep(Duration::from_secs(ctx.config.seconds_between_file_scans)).await;
     }
 }
 
-async fn run_job_for_files(ctx: &AppContext, job: FilescanJob) -> Result<()> {
+/// Finishes the interrupted filescan jobs, or runs a new full scan when there are none.
+pub async fn scan(ctx: &AppContext) -> Result<()> {
+    let started_at = Instant::now();
+    let mut discovered_files = 0;
+    let mut unfinished_scan_jobs = get_unfinished_filescan_jobs(&ctx.db).await?;
+    if unfinished_scan_jobs.is_empty() {
+        info!(
+            "Starting new full scan for files on {}",
+            ctx.config.input_folder.clone()
+        );
+        let job = FilescanJob {
+            id: Uuid::new_v4(),
+            full_path: ctx.config.input_folder.clone(),
+            created_at: time::now(),
+            finished_at: None,
+        };
+        upsert_filescan_job(&ctx.db, job.clone()).await?;
+        unfinished_scan_jobs.push(job);
+    }
+    for job in unfinished_scan_jobs {
+        run_job_for_folders(ctx, &job).await?;
+        discovered_files += run_job_for_files(ctx, job.clone()).await?;
+        upsert_filescan_job(
+            &ctx.db,
+            FilescanJob {
+                finished_at: Some(time::now()),
+                ..job
+            },
+        )
+        .await?;
+    }
+    debug!("Done scanning all filescanjobs");
+    pair_live_photos(ctx).await?;
+    associate_sidecars(ctx).await?;
+    ctx.metrics.record_scan(
+        started_at.elapsed(),
+        discovered_files,
+        OffsetDateTime::now_utc().unix_timestamp(),
+    );
+    Ok(())
+}
+
+async fn run_job_for_files(ctx: &AppContext, job: FilescanJob) -> Result<i64> {
     let all_folders = get_folders(&ctx.db).await?;
     let (tx, mut rx) = tokio::sync::mpsc::channel::<File>(10);
     debug!("Going to search for files on {:?}", all_folders);
@@ -71,7 +88,7 @@ async fn run_job_for_files(ctx: &AppContext, job: FilescanJob) -> Result<()> {
         count += 1;
     }
     info!("Found {count} files.");
-    Ok(())
+    Ok(count)
 }
 
 fn process_folder_for_file_job(job: &FilescanJob, folder: &Folder, tx: Sender<File>) {
@@ -243,6 +260,8 @@ impl EntryProperties<'_> {
             updated_at: time::now(),
             file_modified_at: self.modified_date()?,
             filescan_job_id,
+            mime_type: None,
+            captured_at: None,
         })
     }
 }
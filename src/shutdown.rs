@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+/// Shutdown state shared by the processor and the running conversions. Once requested, no new
+/// work is claimed; once killing, running conversions are terminated.
+#[derive(Clone, Default, Debug)]
+pub struct Shutdown {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Default, Debug)]
+struct ShutdownState {
+    requested: AtomicBool,
+    killing: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn is_killing(&self) -> bool {
+        self.state.killing.load(Ordering::SeqCst)
+    }
+
+    pub async fn requested(&self) {
+        let notified = self.state.notify.notified();
+        if self.is_requested() {
+            return;
+        }
+        notified.await;
+    }
+
+    fn request(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    fn kill(&self) {
+        self.state.killing.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Requests a shutdown on SIGTERM or SIGINT, and kills running conversions once the grace period
+/// is over or a second signal arrives.
+pub async fn listen_for_signals(shutdown: Shutdown, grace_period: Duration) -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+    info!(
+        "Shutdown requested, waiting up to {} seconds for running conversions to finish...",
+        grace_period.as_secs()
+    );
+    shutdown.request();
+
+    tokio::select! {
+        _ = sleep(grace_period) => {},
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+    warn!("Killing running conversions.");
+    shutdown.kill();
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use crate::command_runner::CommandLine;
+use crate::db;
+use crate::processor::FileToBeProcessed;
+use crate::AppContext;
+use anyhow::Result;
+use std::path::Path;
+
+/// Extensions of the files holding metadata about a media file of the same folder: XMP written by
+/// photo editors, AAE edits from Apple devices and JSON from Google Takeout.
+pub const SIDECAR_EXTENSIONS: [&str; 3] = ["xmp", "aae", "json"];
+
+/// Links the sidecars discovered by the scanner to their media file, which are not converted on
+/// their own.
+pub async fn associate_sidecars(ctx: &AppContext) -> Result<u64> {
+    let count = db::associate_sidecars(&ctx.db, &SIDECAR_EXTENSIONS).await?;
+    if count != 0 {
+        info!("Associated {count} sidecar files.");
+    }
+    Ok(count)
+}
+
+/// Writes the metadata of the sidecars of a file into its output. AAE files describe edits that
+/// only Apple software applies, so they are only recorded.
+pub fn sidecar_commands(file: &FileToBeProcessed, output: &Path) -> Vec<CommandLine> {
+    file.sidecars
+        .iter()
+        .filter_map(|sidecar| match extension(sidecar).as_deref() {
+            Some("xmp") => Some(copy_xmp(sidecar, output)),
+            Some("json") => Some(copy_takeout_json(sidecar, output)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether some sidecars of the file have metadata to write into its outputs, which then cannot be
+/// links to the source.
+pub fn has_sidecar_metadata(file: &FileToBeProcessed) -> bool {
+    file.sidecars
+        .iter()
+        .any(|sidecar| matches!(extension(sidecar).as_deref(), Some("xmp" | "json")))
+}
+
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+/// The orientation is left out, as outputs are already rotated.
+fn copy_xmp(sidecar: &str, output: &Path) -> CommandLine {
+    CommandLine::new("exiftool")
+        .args(["-overwrite_original", "-TagsFromFile"])
+        .arg(sidecar)
+        .args([
+            "-xmp:all",
+            "--Orientation",
+            "-DateTimeOriginal<XMP:DateTimeOriginal",
+            "-Rating<XMP:Rating",
+        ])
+        .arg(output)
+}
+
+/// Takeout stores dates as Unix timestamps, and a zero location when there is none.
+fn copy_takeout_json(sidecar: &str, output: &Path) -> CommandLine {
+    CommandLine::new("exiftool")
+        .args(["-overwrite_original", "-d", "%s", "-TagsFromFile"])
+        .arg(sidecar)
+        .args([
+            "-DateTimeOriginal<PhotoTakenTimeTimestamp",
+            "-CreateDate<PhotoTakenTimeTimestamp",
+            "-GPSLatitude<${GeoDataLatitude;$_=undef unless $_}",
+            "-GPSLatitudeRef<${GeoDataLatitude;$_=undef unless $_}",
+            "-GPSLongitude<${GeoDataLongitude;$_=undef unless $_}",
+            "-GPSLongitudeRef<${GeoDataLongitude;$_=undef unless $_}",
+            "-GPSAltitude<${GeoDataAltitude;$_=undef unless $_}",
+            "-ImageDescription<Description",
+        ])
+        .arg(output)
+}
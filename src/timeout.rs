@@ -0,0 +1,54 @@
+use crate::config::parse_preset_values;
+use crate::exiftool::Exiftool;
+use crate::AppContext;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const MAX_TIMEOUT_SECONDS: f64 = 7.0 * 24.0 * 3600.0;
+
+/// Computes how long a conversion may run before it is killed. The base timeout of the media
+/// type grows with the megapixels of images and the duration of videos and audio, and is then
+/// scaled by the multiplier of the preset.
+pub struct TimeoutPolicy {
+    image_seconds: f64,
+    image_seconds_per_megapixel: f64,
+    video_seconds: f64,
+    video_seconds_per_media_second: f64,
+    preset_multipliers: HashMap<String, f64>,
+}
+
+impl TimeoutPolicy {
+    pub fn new(ctx: &AppContext) -> TimeoutPolicy {
+        let config = &ctx.config;
+        TimeoutPolicy {
+            image_seconds: config.seconds_image_conversion_timeout as f64,
+            image_seconds_per_megapixel: config.seconds_image_conversion_timeout_per_megapixel,
+            video_seconds: config.seconds_video_conversion_timeout as f64,
+            video_seconds_per_media_second: config.seconds_video_conversion_timeout_per_second,
+            preset_multipliers: parse_preset_values(
+                "PRESET_TIMEOUT_MULTIPLIERS",
+                &config.preset_timeout_multipliers,
+            ),
+        }
+    }
+
+    pub fn timeout(&self, preset_name: &str, exif: &Exiftool) -> Duration {
+        let seconds = if exif.mime_type.contains("video") || exif.mime_type.contains("audio") {
+            self.video_seconds
+                + self.video_seconds_per_media_second * exif.duration.unwrap_or_default()
+        } else {
+            self.image_seconds
+                + self.image_seconds_per_megapixel * exif.megapixels().unwrap_or_default()
+        };
+        let multiplier = self
+            .preset_multipliers
+            .get(preset_name)
+            .copied()
+            .unwrap_or(1.0);
+        let seconds = seconds * multiplier;
+        if seconds.is_nan() {
+            return Duration::from_secs_f64(MAX_TIMEOUT_SECONDS);
+        }
+        Duration::from_secs_f64(seconds.clamp(1.0, MAX_TIMEOUT_SECONDS))
+    }
+}
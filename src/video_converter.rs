@@ -1,66 +1,138 @@
-use crate::processor::{CommandRunner, FileToBeProcessed, ProcessingMetrics, ProcessingResult, VideoMetrics};
+use crate::color::ColorSpace;
+use crate::command_runner::{CommandLine, CommandRunner};
+use crate::converter::{Converter, ExtraOutput, Throughput};
+use crate::crop::Crops;
+use crate::image_converter::resize_size;
+use crate::image_encoding::JpegEncodings;
+use crate::live_photo::LIVE_PRESET_NAME;
+use crate::native_image::ImageOutput;
+use crate::placeholder::with_placeholder;
+use crate::processor::{FileToBeProcessed, ProcessingMetrics, ProcessingResult, VideoMetrics};
+use crate::sidecar::{has_sidecar_metadata, sidecar_commands};
 use crate::AppContext;
-use rayon::iter::ParallelIterator;
-use rayon::prelude::IntoParallelIterator;
-use rayon::ThreadPool;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-pub struct VideoConverterProcessor {
-    pool: ThreadPool,
-}
-
-impl VideoConverterProcessor {
-    pub fn new(ctx: &AppContext) -> VideoConverterProcessor {
-        VideoConverterProcessor {
-            pool: rayon::ThreadPoolBuilder::new()
-                .num_threads(ctx.config.video_converter_threads)
-                .build()
-                .unwrap_or_else(|e| panic!("Failure initing threadpool for video processing: {e}")),
-        }
-    }
+/// Typical compression of libx264 at its default quality, in bits per pixel of each frame.
+const H264_BITS_PER_PIXEL: f64 = 0.1;
+/// Bitrate of the default AAC audio track.
+const AAC_BYTES_PER_SECOND: f64 = 16_000.0;
+/// Assumed when the frame rate of the source is unknown.
+const DEFAULT_FRAME_RATE: f64 = 30.0;
+/// Time of the poster frame, skipping the fade in or shaky start of most videos.
+const POSTER_FRAME_SECONDS: f64 = 1.0;
+/// Written in place of the extension of the video output, e.g. `clip.poster.jpg`.
+const POSTER_FRAME_EXTENSION: &str = "poster.jpg";
 
-    pub fn convert_files(&self, files: Vec<FileToBeProcessed>) -> Vec<ProcessingResult> {
-        let mut r: Vec<ProcessingResult> = vec![];
+pub struct VideoConverter {
+    /// The poster frames use the crop and encoding of the image outputs of the preset.
+    poster_frames: Option<(Crops, JpegEncodings)>,
+}
 
-        self.pool.install(|| {
-            r = files
-                .into_par_iter()
-                .map(|file| VideoConverter::from(&file).run())
-                .collect();
-        });
-        r
+impl VideoConverter {
+    pub fn new(ctx: &AppContext) -> VideoConverter {
+        let poster_frames = ctx.config.enable_video_poster_frames
+            .then(|| (Crops::new(&ctx.config), JpegEncodings::new(&ctx.config)));
+        VideoConverter { poster_frames }
     }
 }
 
-pub struct VideoConverter<'a> {
-    file: &'a FileToBeProcessed<'a>,
-}
+impl Converter for VideoConverter {
+    fn name(&self) -> &'static str {
+        "video"
+    }
 
-impl VideoConverter<'_> {
-    pub fn from<'a>(file: &'a FileToBeProcessed) -> VideoConverter<'a> {
-        VideoConverter { file }
+    fn handles(&self, file: &FileToBeProcessed) -> bool {
+        file.is_video()
     }
 
-    pub fn run(&self) -> ProcessingResult {
-        let result = self.run_using_hw_or_sw_transcoding();
-        self.add_metrics(result)
+    fn output_extension(&self, _file: &FileToBeProcessed) -> String {
+        "mp4".to_owned()
     }
 
-    fn run_using_hw_or_sw_transcoding(&self) -> ProcessingResult {
-        let hw_transcoding = self.run_hw_transcoding_intel();
-        if hw_transcoding.has_succeeded {
-            return hw_transcoding;
+    /// Sources already satisfying the preset are passed through or remuxed. Otherwise, Intel
+    /// hardware transcoding first, software transcoding as a fallback.
+    fn commands(&self, file: &FileToBeProcessed, output: &Path) -> Vec<(&'static str, CommandRunner)> {
+        let runner = || {
+            CommandRunner::build(file.output_folder, file.shutdown)
+                .with_timeout(file.timeout)
+                .create_folder(file.output_folder_path())
+        };
+        let mut commands = vec![];
+        match compatibility(file) {
+            Compatibility::PassThrough => commands.push((
+                "passthrough",
+                runner()
+                    .link_or_copy(file.file_full_path(), output)
+                    .copy_file_modification_date(file.file_full_path(), output)
+                    .pass_through(),
+            )),
+            Compatibility::Remux => commands.push((
+                "remux",
+                runner()
+                    .with(remux_video(file, output))
+                    .with(copy_metadata(file, output))
+                    .with_all(sidecar_commands(file, output))
+                    .copy_file_modification_date(file.file_full_path(), output)
+                    .pass_through(),
+            )),
+            Compatibility::Transcode => {}
+        }
+        for (backend, transcoding) in [
+            ("vaapi", convert_video_intel_hw_transcoding(file, output)),
+            ("libx264", convert_video_software_transcoding(file, output)),
+        ] {
+            commands.push((
+                backend,
+                runner()
+                    .with(transcoding)
+                    .with(copy_metadata(file, output))
+                    .with_all(sidecar_commands(file, output))
+                    .copy_file_modification_date(file.file_full_path(), output),
+            ));
         }
+        commands
+    }
 
-        info!("video conversion for {} has failed using hw transcoding, fallback to software transcoding...", self.file.file_full_path());
-        self.run_software_transcoding()
+    /// The poster frame, extracted as a PNG then cropped and encoded to a JPEG in process. Videos
+    /// are in BT.709, whose primaries are the ones of sRGB.
+    fn extra_outputs(&self, file: &FileToBeProcessed) -> Vec<ExtraOutput> {
+        let (crops, encodings) = match &self.poster_frames {
+            Some(poster_frames) => poster_frames,
+            None => return vec![],
+        };
+        let output = ImageOutput {
+            size: resize_size(file.preset_name),
+            crop: crops.preset(file.preset_name),
+            color_space: ColorSpace::Srgb,
+            encoding: encodings.preset(file.preset_name),
+        };
+        let temporary_path = file.temporary_output_path(POSTER_FRAME_EXTENSION);
+        let command = CommandRunner::build(file.output_folder, file.shutdown)
+            .with_timeout(file.timeout)
+            .create_folder(file.output_folder_path())
+            .with(extract_frame(file, &output, &temporary_path))
+            .encode_natively(&temporary_path, output);
+        vec![ExtraOutput {
+            name: "poster frame",
+            path: poster_frame_path(file),
+            temporary_path,
+            command,
+        }]
     }
 
-    fn add_metrics(&self, result: ProcessingResult) -> ProcessingResult {
+    fn parse_result(&self, file: &FileToBeProcessed, result: ProcessingResult) -> ProcessingResult {
         if !result.has_succeeded {
             return result;
         }
+        let poster_frame = poster_frame_path(file);
+        let result = if result.extra_outputs.contains(&poster_frame) {
+            with_placeholder(file, result, &poster_frame)
+        } else {
+            result
+        };
 
-        let maybe_fps = self.parse_fps(&result.command_log);
+        let maybe_fps = parse_fps(&result.command_log);
 
         if let Some(fps) = maybe_fps {
             result.with_metrics(ProcessingMetrics::Video(VideoMetrics { fps }))
@@ -69,129 +141,183 @@ impl VideoConverter<'_> {
         }
     }
 
-    fn parse_fps(&self, command_log: &str) -> Option<u32> {
-        let mut statistics_line = "";
-        for line in command_log.lines() {
-            if line.starts_with("frame=") {
-                statistics_line = line;
-            }
+    fn estimate_output_size(&self, file: &FileToBeProcessed) -> Option<u64> {
+        let (width, height) = (file.exif.image_width?, file.exif.image_height?);
+        let duration = file.exif.duration?;
+        // The largest side is scaled to the preset size, keeping the aspect ratio.
+        let size = scale_size(file.preset_name) as f64;
+        let pixels = size * size * width.min(height) / width.max(height);
+        let frame_rate = file.exif.video_frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+        let video_bytes = pixels * frame_rate * duration * H264_BITS_PER_PIXEL / 8.0;
+        Some((video_bytes + AAC_BYTES_PER_SECOND * duration) as u64)
+    }
+
+    fn estimate_duration(&self, file: &FileToBeProcessed, throughput: &Throughput) -> Option<Duration> {
+        let frames = file.exif.duration? * file.exif.video_frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+        Duration::try_from_secs_f64(frames / throughput.fps).ok()
+    }
+}
+
+enum Compatibility {
+    Transcode,
+    Remux,
+    PassThrough,
+}
+
+/// A single H.264 4:2:0 video stream fitting the preset, with AAC audio if any, needs no
+/// transcoding: it is passed through when already in MP4 without sidecar metadata, and remuxed
+/// into MP4 otherwise.
+fn compatibility(file: &FileToBeProcessed) -> Compatibility {
+    let probe = match ffprobe::ffprobe(file.file_full_path()) {
+        Ok(probe) => probe,
+        Err(e) => {
+            debug!("Failure probing {}, it will be transcoded: {e}", file.file_full_path());
+            return Compatibility::Transcode;
         }
-        let statistics_lines = statistics_line.split("\r").collect::<Vec<&str>>();
-        let mut fps_sum: u32 = 0;
-        let mut fps_elements_count: u32 = 0;
-        for line in statistics_lines {
-            let maybe_fps = self.parse_single_fps_line(line);
-            match maybe_fps {
-                Some(fps) if fps > 1 => {
-                    fps_sum += fps;
-                    fps_elements_count += 1;
-                }
-                _ => { continue; }
-            }
+    };
+    let size = scale_size(file.preset_name) as i64;
+    let video_streams: Vec<&ffprobe::Stream> = probe
+        .streams
+        .iter()
+        .filter(|s| s.codec_type.as_deref() == Some("video") && s.disposition.attached_pic == 0)
+        .collect();
+    let is_compatible_video = match video_streams.as_slice() {
+        [video] => {
+            video.codec_name.as_deref() == Some("h264")
+                && video.pix_fmt.as_deref() == Some("yuv420p")
+                && matches!((video.width, video.height), (Some(w), Some(h)) if w.max(h) <= size)
         }
+        _ => false,
+    };
+    let is_compatible_audio = probe
+        .streams
+        .iter()
+        .filter(|s| s.codec_type.as_deref() == Some("audio"))
+        .all(|s| s.codec_name.as_deref() == Some("aac"));
+    if !is_compatible_video || !is_compatible_audio {
+        return Compatibility::Transcode;
+    }
+    let is_mp4 = probe.format.format_name.split(',').any(|f| f == "mp4")
+        && matches!(file.file.extension.as_str(), "mp4" | "m4v");
+    // The metadata of the sidecars is written into a remuxed copy, never into the source.
+    if is_mp4 && !has_sidecar_metadata(file) {
+        Compatibility::PassThrough
+    } else {
+        Compatibility::Remux
+    }
+}
 
-        if fps_sum > 0 {
-            Some(fps_sum/fps_elements_count)
-        } else {
-            None
-        }
+/// Path of the poster frame of a video, next to its output.
+pub fn poster_frame_path(file: &FileToBeProcessed) -> PathBuf {
+    file.output_path(POSTER_FRAME_EXTENSION)
+}
+
+/// Writes a frame as a PNG scaled to cover the output, taken early unless the video is shorter.
+fn extract_frame(file: &FileToBeProcessed, output: &ImageOutput, poster_frame: &Path) -> CommandLine {
+    let seconds = POSTER_FRAME_SECONDS.min(file.exif.duration.unwrap_or(0.0) / 2.0);
+    let (width, height) = match output.crop {
+        Some(crop) => crop.dimensions(output.size),
+        None => (output.size, output.size),
+    };
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y", "-ss", &format!("{seconds:.3}")])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-frames:v", "1", "-vf"])
+        .arg(format!("scale={width}:{height}:force_original_aspect_ratio=increase,format=rgb24"))
+        .args(["-c:v", "png", "-f", "image2"])
+        .arg(poster_frame)
+}
+
+fn scale_size(preset_name: &str) -> u32 {
+    match preset_name {
+        "thumbnail" => 320,
+        LIVE_PRESET_NAME => 720,
+        _ => 1280,
     }
+}
 
-    fn parse_single_fps_line(&self, line: &str) -> Option<u32> {
-        let maybe_fps_index = line.find("fps=");
-        let maybe_q_index = line.find(" q=");
-        if let (Some(fps_index), Some(q_index)) = (maybe_fps_index, maybe_q_index) {
-            line[fps_index..q_index]
-                .replace("fps=", "")
-                .parse::<u32>()
-                .map_err(|e| debug!("Failure extracting FPS from video conversion: {}",e))
-                .ok()
-        } else {
-            None
+fn parse_fps(command_log: &str) -> Option<u32> {
+    let mut statistics_line = "";
+    for line in command_log.lines() {
+        if line.starts_with("frame=") {
+            statistics_line = line;
+        }
+    }
+    let statistics_lines = statistics_line.split("\r").collect::<Vec<&str>>();
+    let mut fps_sum: u32 = 0;
+    let mut fps_elements_count: u32 = 0;
+    for line in statistics_lines {
+        let maybe_fps = parse_single_fps_line(line);
+        match maybe_fps {
+            Some(fps) if fps > 1 => {
+                fps_sum += fps;
+                fps_elements_count += 1;
+            }
+            _ => { continue; }
         }
     }
 
-    fn run_hw_transcoding_intel(&self) -> ProcessingResult {
-        CommandRunner::build(self.file.output_folder)
-            .with(self.define_input_and_output_paths())
-            .with(match self.file.preset_name {
-                "thumbnail" => self.convert_video_preview_intel_hw_transcoding_thumbnail(),
-                _ => self.convert_video_preview_intel_hw_transcoding_preview(),
-            })
-            .with(self.copy_metadata())
-            .with(self.copy_file_modification_date())
-            .run()
-    }
-
-    fn run_software_transcoding(&self) -> ProcessingResult {
-        CommandRunner::build(self.file.output_folder)
-            .with(self.define_input_and_output_paths())
-            .with(match self.file.preset_name {
-                "thumbnail" => self.convert_video_preview_software_transcoding_thumbnail(),
-                _ => self.convert_video_preview_software_transcoding_preview(),
-            })
-            .with(self.copy_metadata())
-            .with(self.copy_file_modification_date())
-            .run()
-    }
-
-    fn define_input_and_output_paths(&self) -> String {
-        let output_filepath = self
-            .file
-            .relative_path_with_file_stem_and_a_given_extension("mp4");
-        format!(
-            r#"mkdir -p "{}"
-input="{}"
-output="{}""#,
-            self.file.relative_path(),
-            self.file.file_full_path(),
-            output_filepath
-        )
-    }
-
-    fn copy_metadata(&self) -> &str {
-        r#"exiftool -overwrite_original -TagsFromFile "$input" "-all:all>all:all" "$output""#
-    }
-
-    fn convert_video_preview_intel_hw_transcoding_preview(&self) -> &str {
-        r#"ffmpeg -nostdin -y -noautorotate \
-    -hwaccel vaapi -hwaccel_device /dev/dri/renderD128 \
-    -hwaccel_output_format vaapi \
-    -i "$input" \
-    -vf "scale_vaapi=w='if(gt(iw,ih),1280,trunc(oh*a/2)*2)':h='if(gt(iw,ih),trunc(ow/a/2)*2,1280)':format=nv12" -c:v h264_vaapi \
-    -movflags use_metadata_tags \
-    "$output""#
-    }
-
-    fn convert_video_preview_intel_hw_transcoding_thumbnail(&self) -> &str {
-        r#"ffmpeg -nostdin -y -noautorotate \
-    -hwaccel vaapi -hwaccel_device /dev/dri/renderD128 \
-    -hwaccel_output_format vaapi \
-    -i "$input" \
-    -vf "scale_vaapi=w='if(gt(iw,ih),320,trunc(oh*a/2)*2)':h='if(gt(iw,ih),trunc(ow/a/2)*2,320)':format=nv12" -c:v h264_vaapi \
-    -movflags use_metadata_tags \
-    "$output""#
-    }
-
-    fn convert_video_preview_software_transcoding_preview(&self) -> &str {
-        r#"ffmpeg -nostdin -y -noautorotate \
-   -i "$input" \
-   -vf "scale=w='if(gt(iw,ih),1280,trunc(oh*a/2)*2)':h='if(gt(iw,ih),trunc(ow/a/2)*2,1280)'" -c:v libx264 \
-   -pix_fmt yuv420p \
-   -movflags use_metadata_tags \
-   "$output""#
-    }
-
-    fn convert_video_preview_software_transcoding_thumbnail(&self) -> &str {
-        r#"ffmpeg -nostdin -y -noautorotate \
-   -i "$input" \
-   -vf "scale=w='if(gt(iw,ih),320,trunc(oh*a/2)*2)':h='if(gt(iw,ih),trunc(ow/a/2)*2,320)'" -c:v libx264 \
-   -pix_fmt yuv420p \
-   -movflags use_metadata_tags \
-   "$output""#
-    }
-
-    fn copy_file_modification_date(&self) -> &str {
-        r#"touch -r "$input" "$output""#
+    if fps_sum > 0 {
+        Some(fps_sum/fps_elements_count)
+    } else {
+        None
     }
 }
+
+fn parse_single_fps_line(line: &str) -> Option<u32> {
+    let maybe_fps_index = line.find("fps=");
+    let maybe_q_index = line.find(" q=");
+    if let (Some(fps_index), Some(q_index)) = (maybe_fps_index, maybe_q_index) {
+        line[fps_index..q_index]
+            .replace("fps=", "")
+            .parse::<u32>()
+            .map_err(|e| debug!("Failure extracting FPS from video conversion: {}",e))
+            .ok()
+    } else {
+        None
+    }
+}
+
+fn copy_metadata(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    CommandLine::new("exiftool")
+        .args(["-overwrite_original", "-TagsFromFile"])
+        .arg(file.file_full_path())
+        .arg("-all:all>all:all")
+        .arg(output)
+}
+
+fn remux_video(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y", "-noautorotate"])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-map", "0:v:0", "-map", "0:a?", "-c", "copy", "-map_metadata", "0"])
+        .args(["-movflags", "use_metadata_tags"])
+        .arg(output)
+}
+
+fn convert_video_intel_hw_transcoding(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    let size = scale_size(file.preset_name);
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y", "-noautorotate"])
+        .args(["-hwaccel", "vaapi", "-hwaccel_device", "/dev/dri/renderD128"])
+        .args(["-hwaccel_output_format", "vaapi"])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-vf", &format!("scale_vaapi=w='if(gt(iw,ih),{size},trunc(oh*a/2)*2)':h='if(gt(iw,ih),trunc(ow/a/2)*2,{size})':format=nv12"), "-c:v", "h264_vaapi"])
+        .args(["-movflags", "use_metadata_tags"])
+        .arg(output)
+}
+
+fn convert_video_software_transcoding(file: &FileToBeProcessed, output: &Path) -> CommandLine {
+    let size = scale_size(file.preset_name);
+    CommandLine::new("ffmpeg")
+        .args(["-nostdin", "-y", "-noautorotate"])
+        .arg("-i")
+        .arg(file.file_full_path())
+        .args(["-vf", &format!("scale=w='if(gt(iw,ih),{size},trunc(oh*a/2)*2)':h='if(gt(iw,ih),trunc(ow/a/2)*2,{size})'"), "-c:v", "libx264"])
+        .args(["-pix_fmt", "yuv420p"])
+        .args(["-movflags", "use_metadata_tags"])
+        .arg(output)
+}